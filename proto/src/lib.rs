@@ -6,6 +6,101 @@ pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/raftpb.rs"));
 }
 
+impl Snapshot {
+    /// For a given snapshot, determine if it's empty or not.
+    pub fn is_empty(&self) -> bool {
+        self.metadata.as_ref().map_or(0, |m| m.index) == 0
+    }
+}
+
+impl ConfChangeV2 {
+    /// Checks if uses Joint Consensus.
+    ///
+    /// It will return Some if and only if this config change will use Joint Consensus,
+    /// which is the case if it contains more than one change or if the use of Joint
+    /// Consensus was requested explicitly. The bool indicates whether the Joint State
+    /// will be left automatically.
+    pub fn enter_joint(&self) -> Option<bool> {
+        // NB: in theory, more config changes could qualify for the "simple"
+        // protocol but it depends on the config on top of which the changes apply.
+        // For example, adding two learners is not OK if both nodes are part of the
+        // base config (i.e. two voters are turned into learners in the process of
+        // applying the conf change). In practice, these distinctions should not
+        // matter, so we keep it simple and use Joint Consensus liberally.
+        if self.transition() != ConfChangeTransition::Auto || self.changes.len() > 1 {
+            match self.transition() {
+                ConfChangeTransition::Auto | ConfChangeTransition::Implicit => Some(true),
+                ConfChangeTransition::Explicit => Some(false),
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Checks if the configuration change leaves a joint configuration.
+    ///
+    /// This is the case if the ConfChangeV2 is zero, with the possible exception of
+    /// the Context field.
+    pub fn leave_joint(&self) -> bool {
+        self.transition() == ConfChangeTransition::Auto && self.changes.is_empty()
+    }
+}
+
+/// Abstracts over ConfChange and ConfChangeV2, so both can be proposed and applied
+/// through the same code path.
+pub trait ConfChangeI {
+    /// Converts the conf change into a `ConfChangeV2`.
+    fn into_v2(self) -> ConfChangeV2;
+
+    /// Gets the `ConfChangeV2` view of the conf change.
+    fn as_v2(&self) -> std::borrow::Cow<'_, ConfChangeV2>;
+
+    /// Gets the `ConfChange` if it's a `ConfChange`.
+    fn as_v1(&self) -> Option<&ConfChange>;
+}
+
+impl ConfChangeI for ConfChange {
+    #[inline]
+    fn into_v2(self) -> ConfChangeV2 {
+        let mut cc = ConfChangeV2 {
+            context: self.context,
+            ..Default::default()
+        };
+        cc.changes.push(ConfChangeSingle {
+            change_type: self.change_type,
+            node_id: self.node_id,
+        });
+        cc
+    }
+
+    #[inline]
+    fn as_v2(&self) -> std::borrow::Cow<'_, ConfChangeV2> {
+        std::borrow::Cow::Owned(self.clone().into_v2())
+    }
+
+    #[inline]
+    fn as_v1(&self) -> Option<&ConfChange> {
+        Some(self)
+    }
+}
+
+impl ConfChangeI for ConfChangeV2 {
+    #[inline]
+    fn into_v2(self) -> ConfChangeV2 {
+        self
+    }
+
+    #[inline]
+    fn as_v2(&self) -> std::borrow::Cow<'_, ConfChangeV2> {
+        std::borrow::Cow::Borrowed(self)
+    }
+
+    #[inline]
+    fn as_v1(&self) -> Option<&ConfChange> {
+        None
+    }
+}
+
 // pub mod prelude {
 //     pub use crate::raftpb::{
 //         Message, MessageType
@@ -1,7 +1,13 @@
 use crate::tracker::{ProgressTracker, Configuration};
-use crate::errors::Result;
+use crate::errors::{ConfChangeError, Result};
 use raftpb::proto::{ConfChangeSingle, ConfChangeType};
 
+/// Returns true if the configuration is joint, i.e. it has an outgoing half.
+#[inline]
+pub fn joint(cfg: &Configuration) -> bool {
+    !cfg.voters.outgoing.voters.is_empty()
+}
+
 /// Changer facilitates configuration changes.
 pub struct Changer<'a> {
     pub tracker: &'a mut ProgressTracker,
@@ -12,56 +18,303 @@ impl<'a> Changer<'a> {
         Changer { tracker }
     }
 
+    /// Carries out a series of configuration changes that (in aggregate) mutates
+    /// the incoming majority config Voters[0] by at most one. This method will
+    /// return an error if that is not the case, if the resulting quorum is
+    /// zero, or if the configuration is in a joint state (i.e. if there is an
+    /// outgoing configuration).
     pub fn simple(&mut self, changes: &[ConfChangeSingle]) -> Result<(Configuration, Vec<(u64, u64)>)> {
-        let mut cfg = self.tracker.conf().clone();
-        let mut changes_out = Vec::new();
-        for cc in changes {
-            match cc.change_type() {
-                ConfChangeType::AddNode => {
-                    cfg.voters.incoming.voters.insert(cc.node_id);
-                    cfg.learners.remove(&cc.node_id);
-                }
-                ConfChangeType::AddLearnerNode => {
-                    cfg.voters.incoming.voters.remove(&cc.node_id);
-                    cfg.voters.outgoing.voters.remove(&cc.node_id);
-                    cfg.learners.insert(cc.node_id);
-                }
-                ConfChangeType::RemoveNode => {
-                    cfg.voters.incoming.voters.remove(&cc.node_id);
-                    cfg.voters.outgoing.voters.remove(&cc.node_id);
-                    cfg.learners.remove(&cc.node_id);
-                }
-            }
-            changes_out.push((cc.node_id, cc.node_id));
+        if joint(self.tracker.conf()) {
+            return Err(ConfChangeError::SimpleInJoint.into());
         }
+        let mut cfg = self.check_and_copy()?;
+        let changes_out = apply(&mut cfg, changes)?;
+        let old = &self.tracker.conf().voters.incoming.voters;
+        if cfg.voters.incoming.voters.symmetric_difference(old).count() > 1 {
+            return Err(ConfChangeError::TooManyVoterChanges.into());
+        }
+        check_invariants(&cfg)?;
         Ok((cfg, changes_out))
     }
 
-    pub fn enter_joint(&mut self, _auto_leave: bool, changes: &[ConfChangeSingle]) -> Result<(Configuration, Vec<(u64, u64)>)> {
-        let mut cfg = self.tracker.conf().clone();
-        cfg.voters.outgoing = cfg.voters.incoming.clone();
-        // apply changes to incoming
-        for cc in changes {
-            match cc.change_type() {
-                ConfChangeType::AddNode => {
-                    cfg.voters.incoming.voters.insert(cc.node_id);
-                    cfg.learners.remove(&cc.node_id);
-                }
-                ConfChangeType::AddLearnerNode => {
-                    cfg.voters.incoming.voters.remove(&cc.node_id);
-                    cfg.learners.insert(cc.node_id);
-                }
-                ConfChangeType::RemoveNode => {
-                    cfg.voters.incoming.voters.remove(&cc.node_id);
-                    cfg.learners.remove(&cc.node_id);
-                }
-            }
+    /// Verifies that the outgoing (=right) majority config of the joint
+    /// config is empty and initializes it with a copy of the incoming (=left)
+    /// majority config. That is, it transitions from
+    /// ```text
+    ///     (1 2 3)&&()
+    /// ```
+    /// to
+    /// ```text
+    ///     (1 2 3)&&(1 2 3)
+    /// ```.
+    ///
+    /// The supplied changes are then applied to the incoming majority config,
+    /// resulting in a joint configuration that in terms of the Raft thesis[1]
+    /// (Section 4.3) corresponds to `C_{new,old}`.
+    ///
+    /// [1]: https://github.com/ongardie/dissertation/blob/master/online-trim.pdf
+    pub fn enter_joint(&mut self, auto_leave: bool, changes: &[ConfChangeSingle]) -> Result<(Configuration, Vec<(u64, u64)>)> {
+        let mut cfg = self.check_and_copy()?;
+        if joint(&cfg) {
+            return Err(ConfChangeError::AlreadyJoint.into());
         }
-        let mut changes_out = Vec::new();
-        for cc in changes {
-            changes_out.push((cc.node_id, cc.node_id));
+        if cfg.voters.incoming.voters.is_empty() {
+            // We allow adding nodes to an empty config for convenience (testing and
+            // bootstrap), but you can't enter a joint state.
+            return Err(ConfChangeError::ZeroVoters.into());
         }
+        cfg.voters.outgoing = cfg.voters.incoming.clone();
+        let changes_out = apply(&mut cfg, changes)?;
+        cfg.auto_leave = auto_leave;
+        check_invariants(&cfg)?;
+        Ok((cfg, changes_out))
+    }
 
+    /// Transitions out of a joint configuration: the learners staged in
+    /// `learners_next` become learners and the outgoing half is dropped, so
+    /// `C_{new,old}` becomes `C_new`.
+    pub fn leave_joint(&mut self) -> Result<(Configuration, Vec<(u64, u64)>)> {
+        let mut cfg = self.check_and_copy()?;
+        if !joint(&cfg) {
+            return Err(ConfChangeError::NotJoint.into());
+        }
+        cfg.learners.extend(cfg.learners_next.drain());
+        let changes_out = cfg
+            .voters
+            .outgoing
+            .voters
+            .iter()
+            .map(|id| (*id, *id))
+            .collect();
+        cfg.voters.outgoing.voters.clear();
+        cfg.auto_leave = false;
+        check_invariants(&cfg)?;
         Ok((cfg, changes_out))
     }
+
+    /// Copies the tracker's config after making sure it is valid, so that a
+    /// change never starts from an illegal configuration.
+    fn check_and_copy(&self) -> Result<Configuration> {
+        let cfg = self.tracker.conf().clone();
+        check_invariants(&cfg)?;
+        Ok(cfg)
+    }
+}
+
+/// Makes sure that the config is valid: learners and voters are disjoint,
+/// learners_next only holds outgoing voters, and the joint-only fields are
+/// unset outside of a joint config.
+fn check_invariants(cfg: &Configuration) -> Result<()> {
+    for id in &cfg.learners_next {
+        // Any staged learner was staged because it could not be directly added due
+        // to a conflicting voter in the outgoing config.
+        if !cfg.voters.outgoing.voters.contains(id) {
+            return Err(ConfChangeError::LearnerNextNotOutgoing(*id).into());
+        }
+        if cfg.learners.contains(id) {
+            return Err(ConfChangeError::LearnerNextIsLearner(*id).into());
+        }
+    }
+    // Conversely, learners and voters doesn't intersect at all.
+    for id in &cfg.learners {
+        if cfg.voters.outgoing.voters.contains(id) || cfg.voters.incoming.voters.contains(id) {
+            return Err(ConfChangeError::LearnerIsVoter(*id).into());
+        }
+    }
+    if !joint(cfg) {
+        if !cfg.learners_next.is_empty() {
+            return Err(ConfChangeError::LearnersNextNotJoint.into());
+        }
+        if cfg.auto_leave {
+            return Err(ConfChangeError::AutoLeaveNotJoint.into());
+        }
+    }
+    Ok(())
+}
+
+/// Applies a change to the configuration. By convention, changes to voters are always
+/// made to the incoming majority config. Outgoing is either empty or preserves the
+/// outgoing majority configuration while in a joint state.
+fn apply(cfg: &mut Configuration, changes: &[ConfChangeSingle]) -> Result<Vec<(u64, u64)>> {
+    let mut changes_out = Vec::with_capacity(changes.len());
+    for cc in changes {
+        match cc.change_type() {
+            ConfChangeType::AddNode => make_voter(cfg, cc.node_id),
+            ConfChangeType::AddLearnerNode => make_learner(cfg, cc.node_id),
+            ConfChangeType::RemoveNode => remove(cfg, cc.node_id),
+        }
+        changes_out.push((cc.node_id, cc.node_id));
+    }
+    if cfg.voters.incoming.voters.is_empty() {
+        return Err(ConfChangeError::ZeroVoters.into());
+    }
+    Ok(changes_out)
+}
+
+/// Adds or promotes the given ID to be a voter in the incoming majority config.
+fn make_voter(cfg: &mut Configuration, id: u64) {
+    cfg.voters.incoming.voters.insert(id);
+    cfg.learners.remove(&id);
+    cfg.learners_next.remove(&id);
+}
+
+/// Makes the given ID a learner or stages it to be a learner once an active joint
+/// configuration is exited.
+///
+/// The former happens when the peer is not a part of the outgoing config, in which
+/// case we either add a new learner or demote a voter in the incoming config.
+///
+/// The latter case occurs when the configuration is joint and the peer is a voter
+/// in the outgoing config. In that case, we do not want to add the peer as a learner
+/// because then we'd have to track a peer as a voter and learner simultaneously.
+/// Instead, we add the learner to LearnersNext, so that it will be added to Learners
+/// the moment the outgoing config is removed by `leave_joint`.
+fn make_learner(cfg: &mut Configuration, id: u64) {
+    if cfg.learners.contains(&id) {
+        return;
+    }
+    cfg.voters.incoming.voters.remove(&id);
+    cfg.learners.remove(&id);
+    cfg.learners_next.remove(&id);
+    // Use LearnersNext if we can't add the learner to Learners directly, i.e.
+    // if the peer is still tracked as a voter in the outgoing config. It will
+    // be turned into a learner in LeaveJoint().
+    //
+    // Otherwise, add a regular learner right away.
+    if cfg.voters.outgoing.voters.contains(&id) {
+        cfg.learners_next.insert(id);
+    } else {
+        cfg.learners.insert(id);
+    }
+}
+
+/// Removes this peer as a voter or learner from the incoming config.
+fn remove(cfg: &mut Configuration, id: u64) {
+    cfg.voters.incoming.voters.remove(&id);
+    cfg.learners.remove(&id);
+    cfg.learners_next.remove(&id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Error;
+    use crate::util::new_conf_change_single;
+
+    fn new_tracker(voters: &[u64]) -> ProgressTracker {
+        let mut tracker = ProgressTracker::new(256);
+        for &id in voters {
+            let cc = new_conf_change_single(id, ConfChangeType::AddNode);
+            let (cfg, changes) = Changer::new(&mut tracker).simple(&[cc]).unwrap();
+            tracker.apply_conf(cfg, changes, 1);
+        }
+        tracker
+    }
+
+    fn conf_change_err(res: Result<(Configuration, Vec<(u64, u64)>)>) -> ConfChangeError {
+        match res {
+            Err(Error::ConfChange(e)) => e,
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok((cfg, _)) => panic!("unexpected success {:?}", cfg),
+        }
+    }
+
+    #[test]
+    fn test_simple_refusals() {
+        let mut tracker = new_tracker(&[1, 2, 3]);
+        let add = |id| new_conf_change_single(id, ConfChangeType::AddNode);
+        let remove = |id| new_conf_change_single(id, ConfChangeType::RemoveNode);
+
+        let err = conf_change_err(Changer::new(&mut tracker).simple(&[add(4), add(5)]));
+        assert_eq!(err, ConfChangeError::TooManyVoterChanges);
+        // Swapping a voter for another one changes two voters as well.
+        let err = conf_change_err(Changer::new(&mut tracker).simple(&[remove(3), add(4)]));
+        assert_eq!(err, ConfChangeError::TooManyVoterChanges);
+        // Demoting is a single voter change.
+        let learner = new_conf_change_single(3, ConfChangeType::AddLearnerNode);
+        let (cfg, _) = Changer::new(&mut tracker).simple(&[learner]).unwrap();
+        assert!(cfg.learners.contains(&3));
+        assert!(!cfg.voters.incoming.voters.contains(&3));
+
+        let mut tracker = new_tracker(&[1]);
+        let err = conf_change_err(Changer::new(&mut tracker).simple(&[remove(1)]));
+        assert_eq!(err, ConfChangeError::ZeroVoters);
+
+        let mut tracker = new_tracker(&[1, 2, 3]);
+        let (cfg, changes) = Changer::new(&mut tracker).enter_joint(false, &[add(4)]).unwrap();
+        tracker.apply_conf(cfg, changes, 1);
+        let err = conf_change_err(Changer::new(&mut tracker).simple(&[add(5)]));
+        assert_eq!(err, ConfChangeError::SimpleInJoint);
+    }
+
+    #[test]
+    fn test_enter_and_leave_joint() {
+        let mut tracker = new_tracker(&[1, 2, 3]);
+        let err = conf_change_err(Changer::new(&mut tracker).leave_joint());
+        assert_eq!(err, ConfChangeError::NotJoint);
+
+        // Replace 3 by 4 and demote 2.
+        let changes = [
+            new_conf_change_single(3, ConfChangeType::RemoveNode),
+            new_conf_change_single(4, ConfChangeType::AddNode),
+            new_conf_change_single(2, ConfChangeType::AddLearnerNode),
+        ];
+        let (cfg, out) = Changer::new(&mut tracker).enter_joint(true, &changes).unwrap();
+        tracker.apply_conf(cfg, out, 1);
+        let cs = tracker.conf_state();
+        assert_eq!(cs.voters, vec![1, 4]);
+        assert_eq!(cs.voters_outgoing, vec![1, 2, 3]);
+        assert!(cs.learners.is_empty());
+        assert_eq!(cs.learners_next, vec![2]);
+        assert!(cs.auto_leave);
+        let err = conf_change_err(Changer::new(&mut tracker).enter_joint(true, &[]));
+        assert_eq!(err, ConfChangeError::AlreadyJoint);
+
+        let (cfg, out) = Changer::new(&mut tracker).leave_joint().unwrap();
+        tracker.apply_conf(cfg, out, 1);
+        let cs = tracker.conf_state();
+        assert_eq!(cs.voters, vec![1, 4]);
+        assert!(cs.voters_outgoing.is_empty());
+        assert_eq!(cs.learners, vec![2]);
+        assert!(cs.learners_next.is_empty());
+        assert!(!cs.auto_leave);
+        assert_eq!(tracker.ids().len(), 3);
+        assert!(tracker.get(3).is_none());
+
+        let remove_all: Vec<_> = [1, 4]
+            .iter()
+            .map(|&id| new_conf_change_single(id, ConfChangeType::RemoveNode))
+            .collect();
+        let err = conf_change_err(Changer::new(&mut tracker).enter_joint(false, &remove_all));
+        assert_eq!(err, ConfChangeError::ZeroVoters);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut cfg = new_tracker(&[1, 2]).conf().clone();
+        assert!(check_invariants(&cfg).is_ok());
+
+        cfg.learners.insert(2);
+        assert!(matches!(
+            check_invariants(&cfg),
+            Err(Error::ConfChange(ConfChangeError::LearnerIsVoter(2)))
+        ));
+        cfg.learners.clear();
+
+        cfg.learners_next.insert(2);
+        assert!(matches!(
+            check_invariants(&cfg),
+            Err(Error::ConfChange(ConfChangeError::LearnerNextNotOutgoing(2)))
+        ));
+        cfg.voters.outgoing.voters.insert(2);
+        assert!(check_invariants(&cfg).is_ok());
+        cfg.voters.outgoing.voters.clear();
+        cfg.learners_next.clear();
+
+        cfg.auto_leave = true;
+        assert!(matches!(
+            check_invariants(&cfg),
+            Err(Error::ConfChange(ConfChangeError::AutoLeaveNotJoint))
+        ));
+    }
 }
@@ -1,4 +1,7 @@
 use anyhow::{anyhow, Result};
+
+use crate::read_only::ReadOnlyOption;
+
 /// A constant represents invalid id of raft.
 pub const INVALID_ID: u64 = 0;
 
@@ -21,6 +24,54 @@ pub struct Config {
     /// Specify if the leader should check quorum activity. Leader steps down when
     /// quorum is not active for an electionTimeout.
     pub check_quorum: bool,
+
+    /// Enables the Pre-Vote algorithm described in raft thesis section
+    /// 9.6. This prevents disruption when a node that has been partitioned away
+    /// rejoins the cluster.
+    pub pre_vote: bool,
+
+    /// Choose the linearizability mode or the lease mode to read data. If you don't care
+    /// about the read consistency and want a higher read performance, you can use the
+    /// lease mode.
+    ///
+    /// Setting this to `LeaseBased` requires `check_quorum = true`.
+    pub read_only_option: ReadOnlyOption,
+
+    /// Limit the max size of each append message. Smaller value lowers
+    /// the raft recovery cost(initial probing and message lost during normal operation).
+    /// On the other side, it might affect the throughput during normal replication.
+    /// Note: u64::MAX for unlimited.
+    pub max_size_per_msg: u64,
+
+    /// Limit the max number of in-flight append messages during optimistic
+    /// replication phase. The application transportation layer usually has its own sending
+    /// buffer over TCP/UDP. Set to avoid overflowing that sending buffer.
+    pub max_inflight_msgs: usize,
+
+    /// Applied is the last applied index. It should only be set when restarting
+    /// raft. raft will not return entries to the application smaller or equal to
+    /// Applied. If Applied is unset when restarting, raft might return previous
+    /// applied entries. This is a very application dependent configuration.
+    pub applied: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        const HEARTBEAT_TICK: usize = 2;
+        Self {
+            id: 0,
+            heartbeat_tick: HEARTBEAT_TICK,
+            election_tick: HEARTBEAT_TICK * 10,
+            min_election_tick: HEARTBEAT_TICK * 10,
+            max_election_tick: HEARTBEAT_TICK * 20,
+            check_quorum: false,
+            pre_vote: false,
+            read_only_option: ReadOnlyOption::Safe,
+            max_size_per_msg: u64::MAX,
+            max_inflight_msgs: 256,
+            applied: 0,
+        }
+    }
 }
 
 impl Config {
@@ -49,6 +100,14 @@ impl Config {
             )));
         }
 
+        if self.max_inflight_msgs == 0 {
+            return Err(anyhow!("max inflight messages must be greater than 0".to_owned()));
+        }
+
+        if self.max_size_per_msg == 0 {
+            return Err(anyhow!("max size per message must be greater than 0".to_owned()));
+        }
+
         if min_timeout >= max_timeout {
             return Err(anyhow!(format!(
                 "min election tick {} should be less than max election tick {}",
@@ -56,6 +115,12 @@ impl Config {
             )));
         }
 
+        if self.read_only_option == ReadOnlyOption::LeaseBased && !self.check_quorum {
+            return Err(anyhow!(
+                "read_only_option == LeaseBased requires check_quorum == true".to_owned(),
+            ));
+        }
+
         Ok(())
     }
 }
@@ -16,12 +16,53 @@ pub enum StorageError {
     SnapshotTemporarilyUnavailable,
     #[error("log temporarily unavailable")]
     LogTemporarilyUnavailable,
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("storage corrupted: {0}")]
+    Corrupted(String),
+}
+
+/// Reasons for the `Changer` to refuse a configuration change.
+#[derive(Debug, PartialEq, Eq, ThisError)]
+pub enum ConfChangeError {
+    #[error("config is already joint")]
+    AlreadyJoint,
+    #[error("can't leave a non-joint config")]
+    NotJoint,
+    #[error("can't apply simple config change in joint config")]
+    SimpleInJoint,
+    #[error("more than one voter changed without entering joint config")]
+    TooManyVoterChanges,
+    #[error("removed all voters")]
+    ZeroVoters,
+    #[error("{0} is in learners and voters")]
+    LearnerIsVoter(u64),
+    #[error("{0} is in learners_next, but not outgoing")]
+    LearnerNextNotOutgoing(u64),
+    #[error("{0} is in learners_next and learners")]
+    LearnerNextIsLearner(u64),
+    #[error("learners_next must be empty when not joint")]
+    LearnersNextNotJoint,
+    #[error("auto_leave must be false when not joint")]
+    AutoLeaveNotJoint,
 }
 
 #[derive(Debug, ThisError)]
 pub enum Error {
     #[error("storage error: {0}")]
     Store(#[from] StorageError),
+    #[error("raft: proposal dropped")]
+    ProposalDropped,
+    #[error("raft: request snapshot dropped")]
+    RequestSnapshotDropped,
+    #[error("conf change error: {0}")]
+    ConfChange(#[from] ConfChangeError),
     #[error("anyhow error: {0}")]
     Anyhow(#[from] AnyhowError),
 }
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Store(StorageError::Io(e))
+    }
+}
@@ -0,0 +1,13 @@
+pub mod config;
+pub mod confchange;
+pub mod errors;
+pub mod log_unstable;
+pub mod node;
+pub mod quorum;
+pub mod raft;
+pub mod read_only;
+pub mod storage;
+pub mod tracker;
+pub mod util;
+
+pub use quorum::majority::Configuration as MajorityConfig;
@@ -0,0 +1,248 @@
+use raftpb::proto::{Entry, Snapshot};
+
+/// The `unstable.entries[i]` has raft log position `i+unstable.offset`.
+/// Note that `unstable.offset` may be less than the highest log
+/// position in storage; this means that the next write to storage
+/// might need to truncate the log before persisting unstable.entries.
+#[derive(Debug, Default)]
+pub struct Unstable {
+    /// The incoming unstable snapshot, if any.
+    pub snapshot: Option<Snapshot>,
+
+    /// All entries that have not yet been written to storage.
+    pub entries: Vec<Entry>,
+
+    /// The offset from the vector index.
+    pub offset: u64,
+}
+
+impl Unstable {
+    /// Creates a new log of unstable entries.
+    pub fn new(offset: u64) -> Unstable {
+        Unstable {
+            offset,
+            snapshot: None,
+            entries: vec![],
+        }
+    }
+
+    /// Returns the index of the first possible entry in entries
+    /// if it has a snapshot.
+    pub fn maybe_first_index(&self) -> Option<u64> {
+        self.snapshot
+            .as_ref()
+            .map(|snap| snap.metadata.as_ref().map_or(0, |m| m.index) + 1)
+    }
+
+    /// Returns the last index if it has at least one unstable entry or snapshot.
+    pub fn maybe_last_index(&self) -> Option<u64> {
+        match self.entries.len() {
+            0 => self
+                .snapshot
+                .as_ref()
+                .map(|snap| snap.metadata.as_ref().map_or(0, |m| m.index)),
+            len => Some(self.offset + len as u64 - 1),
+        }
+    }
+
+    /// Returns the term of the entry at index idx, if there is any.
+    pub fn maybe_term(&self, idx: u64) -> Option<u64> {
+        if idx < self.offset {
+            let meta = self.snapshot.as_ref()?.metadata.as_ref()?;
+            if idx == meta.index {
+                Some(meta.term)
+            } else {
+                None
+            }
+        } else {
+            self.maybe_last_index().and_then(|last| {
+                if idx > last {
+                    return None;
+                }
+                Some(self.entries[(idx - self.offset) as usize].term)
+            })
+        }
+    }
+
+    /// Clears the unstable entries up to and including `idx` once they have been
+    /// written to storage, as long as `term` still matches the entry at `idx`.
+    pub fn stable_to(&mut self, idx: u64, term: u64) {
+        let t = match self.maybe_term(idx) {
+            Some(t) => t,
+            None => return,
+        };
+        // If idx < offset, the entry is the pending snapshot's and there is nothing
+        // to drop. A mismatched term means the entry has been replaced since.
+        if t == term && idx >= self.offset {
+            let start = idx + 1 - self.offset;
+            self.entries.drain(..start as usize);
+            self.offset = idx + 1;
+        }
+    }
+
+    /// Clears the unstable snapshot once it has been written to storage.
+    pub fn stable_snap_to(&mut self, idx: u64) {
+        if let Some(snap) = &self.snapshot {
+            if snap.metadata.as_ref().map_or(0, |m| m.index) == idx {
+                self.snapshot = None;
+            }
+        }
+    }
+
+    /// From a given snapshot, restores the snapshot to self, but doesn't unpack.
+    pub fn restore(&mut self, snap: Snapshot) {
+        self.entries.clear();
+        self.offset = snap.metadata.as_ref().map_or(0, |m| m.index) + 1;
+        self.snapshot = Some(snap);
+    }
+
+    /// Append entries to unstable, truncate local entries if conflicting.
+    pub fn truncate_and_append(&mut self, ents: &[Entry]) {
+        let after = ents[0].index - 1;
+        if after == self.offset + self.entries.len() as u64 - 1 {
+            // after is the last index in the self.entries, append directly
+            self.entries.extend_from_slice(ents);
+        } else if after < self.offset {
+            // The log is being truncated to before our current offset
+            // portion, so set the offset and replace the entries
+            self.offset = after + 1;
+            self.entries.clear();
+            self.entries.extend_from_slice(ents);
+        } else {
+            // truncate to after and copy to self.entries then append
+            let off = self.offset;
+            self.must_check_outofbounds(off, after + 1);
+            self.entries.truncate((after + 1 - off) as usize);
+            self.entries.extend_from_slice(ents);
+        }
+    }
+
+    /// Returns a slice of entries between the high and low.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `lo` or `hi` are out of bounds.
+    /// Panics if `lo > hi`.
+    pub fn slice(&self, lo: u64, hi: u64) -> &[Entry] {
+        self.must_check_outofbounds(lo, hi);
+        let l = lo as usize;
+        let h = hi as usize;
+        let off = self.offset as usize;
+        &self.entries[l - off..h - off]
+    }
+
+    /// Asserts the `hi` and `lo` values against each other and against the
+    /// entries themselves.
+    pub fn must_check_outofbounds(&self, lo: u64, hi: u64) {
+        if lo > hi {
+            panic!("invalid unstable.slice {} > {}", lo, hi)
+        }
+        let upper = self.offset + self.entries.len() as u64;
+        if lo < self.offset || hi > upper {
+            panic!(
+                "unstable.slice[{}, {}] out of bound[{}, {}]",
+                lo, hi, self.offset, upper
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raftpb::proto::SnapshotMetadata;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            ..Default::default()
+        }
+    }
+
+    fn new_snapshot(index: u64, term: u64) -> Snapshot {
+        Snapshot {
+            metadata: Some(SnapshotMetadata {
+                index,
+                term,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_maybe_term() {
+        let mut u = Unstable::new(5);
+        u.entries = vec![new_entry(5, 1)];
+        assert_eq!(u.maybe_term(5), Some(1));
+        assert_eq!(u.maybe_term(6), None);
+        assert_eq!(u.maybe_term(4), None);
+
+        u.snapshot = Some(new_snapshot(4, 1));
+        assert_eq!(u.maybe_term(4), Some(1));
+        assert_eq!(u.maybe_term(3), None);
+        assert_eq!(u.maybe_first_index(), Some(5));
+        assert_eq!(u.maybe_last_index(), Some(5));
+    }
+
+    #[test]
+    fn test_stable_to() {
+        // (entries, offset, snapshot, stable_to, wentries len, woffset)
+        let tests = vec![
+            (vec![], 0, None, (5, 1), 0, 0),
+            // stable to the first entry
+            (vec![new_entry(5, 1)], 5, None, (5, 1), 0, 6),
+            (vec![new_entry(5, 1), new_entry(6, 1)], 5, None, (5, 1), 1, 6),
+            // stable to the first entry and term mismatch
+            (vec![new_entry(6, 2)], 6, None, (6, 1), 1, 6),
+            // stable to old entry
+            (vec![new_entry(5, 1)], 5, None, (4, 1), 1, 5),
+            // with a pending snapshot
+            (vec![new_entry(5, 1)], 5, Some(new_snapshot(4, 1)), (4, 1), 1, 5),
+        ];
+        for (i, (entries, offset, snapshot, (idx, term), wlen, woffset)) in
+            tests.into_iter().enumerate()
+        {
+            let mut u = Unstable {
+                entries,
+                offset,
+                snapshot,
+            };
+            u.stable_to(idx, term);
+            assert_eq!(u.entries.len(), wlen, "#{}", i);
+            assert_eq!(u.offset, woffset, "#{}", i);
+        }
+    }
+
+    #[test]
+    fn test_truncate_and_append() {
+        // (entries, offset, to_append, woffset, wentries)
+        let tests = vec![
+            // append to the end
+            (vec![new_entry(5, 1)], 5, vec![new_entry(6, 1)], 5, vec![(5, 1), (6, 1)]),
+            // replace the unstable entries
+            (vec![new_entry(5, 1)], 5, vec![new_entry(5, 2)], 5, vec![(5, 2)]),
+            (vec![new_entry(5, 1)], 5, vec![new_entry(4, 2)], 4, vec![(4, 2)]),
+            // truncate the existing entries and append
+            (
+                vec![new_entry(5, 1), new_entry(6, 1), new_entry(7, 1)],
+                5,
+                vec![new_entry(6, 2)],
+                5,
+                vec![(5, 1), (6, 2)],
+            ),
+        ];
+        for (i, (entries, offset, to_append, woffset, wentries)) in tests.into_iter().enumerate() {
+            let mut u = Unstable {
+                entries,
+                offset,
+                snapshot: None,
+            };
+            u.truncate_and_append(&to_append);
+            assert_eq!(u.offset, woffset, "#{}", i);
+            let got: Vec<(u64, u64)> = u.entries.iter().map(|e| (e.index, e.term)).collect();
+            assert_eq!(got, wentries, "#{}", i);
+        }
+    }
+}
@@ -1,25 +1,15 @@
-use raftpb::proto::{ConfState, Message};
-use slog::{info, o, Drain, Logger};
+use prost::Message as ProstMessage;
+use raftpb::proto::{ConfChange, ConfChangeV2, ConfState, EntryType, Message};
+use slog::{info, o, warn, Drain, Logger};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     time::{Duration, Instant},
 };
 use tokio::{sync::mpsc, time::timeout};
 
-mod config;
-mod confchange;
-mod node;
-mod quorum;
-mod raft;
-mod tracker;
-mod storage;
-mod errors;
-mod util;
-
-use crate::storage::{MemStorage, Storage};
-use quorum::majority::Configuration as MajorityConfig;
-
-use node::Node;
+use consensus_sample::config;
+use consensus_sample::node::Node;
+use consensus_sample::storage::MemStorage;
 
 type ProposeCallback = Box<dyn Fn() + Send>;
 
@@ -31,7 +21,7 @@ enum Msg {
     // Here we don't use Raft Message, so use dead_code to
     // avoid the compiler warning.
     #[allow(dead_code)]
-    Raft(Message),
+    Raft(Box<Message>),
 }
 
 #[tokio::main]
@@ -42,10 +32,12 @@ async fn main() {
     let drain = slog_async::Async::new(drain).build().fuse();
     let logger = slog::Logger::root(drain, o!());
     // Create a storage for Raft, and here we just use a simple memory storage.
-    // You need to build your own persistent storage in your production.
-    // Please check the Storage trait in src/storage.rs to see how to implement one.
-    let mut conf_state = ConfState::default();
-    conf_state.voters = vec![1];
+    // In production use a persistent one such as `storage::file::FileStorage`, or
+    // check the Storage trait in src/storage.rs to see how to implement your own.
+    let conf_state = ConfState {
+        voters: vec![1],
+        ..Default::default()
+    };
     let storage = MemStorage::new_with_conf_state(conf_state);
 
 
@@ -56,8 +48,9 @@ async fn main() {
         min_election_tick: 25,
         max_election_tick: 30,
         check_quorum: false,
+        ..Default::default()
     };
-    let mut node = Node::new(&conf, storage, &logger).unwrap();
+    let mut node = Node::new(&conf, storage.clone(), &logger).unwrap();
 
     let (sender, mut receiver) = mpsc::unbounded_channel();
 
@@ -66,18 +59,21 @@ async fn main() {
     let mut r_timeout = Duration::from_millis(100);
 
     // Make another tokio task to make a raft request
-    tokio::task::spawn(send_propose(logger, sender));
+    tokio::task::spawn(send_propose(logger.clone(), sender));
 
     // Use a HashMap to hold the `propose` callbacks.
     let mut cbs = HashMap::new();
+    // Proposals are dropped while there is no leader or a leader transfer is in
+    // progress, so keep them here until raft accepts them.
+    let mut proposals = VecDeque::new();
 
     loop {
         match timeout(r_timeout, receiver.recv()).await {
             Ok(Some(Msg::Propose { id, cb })) => {
                 cbs.insert(id, cb);
-                // node.raft.propose(vec![], vec![id]).unwrap();
+                proposals.push_back(id);
             }
-            Ok(Some(Msg::Raft(m))) => node.raft.step(m).unwrap(),
+            Ok(Some(Msg::Raft(m))) => node.raft.step(*m).unwrap(),
             Err(_) => (),
             _ => (),
         }
@@ -90,9 +86,83 @@ async fn main() {
         } else {
             r_timeout -= d;
         }
+
+        while let Some(&id) = proposals.front() {
+            if let Err(e) = node.propose(vec![], vec![id]) {
+                warn!(logger, "failed to propose, will retry"; "id" => id, "err" => %e);
+                break;
+            }
+            proposals.pop_front();
+        }
+
+        on_ready(&mut node, &storage, &mut cbs);
     }
 }
 
+fn on_ready(
+    node: &mut Node<MemStorage>,
+    storage: &MemStorage,
+    cbs: &mut HashMap<u8, ProposeCallback>,
+) {
+    if !node.has_ready() {
+        return;
+    }
+
+    // Get the `Ready` with `Node::ready` interface.
+    let mut ready = node.ready();
+
+    // Send out the messages come from the node. There is no other peer in this
+    // example, so there is nothing to send.
+    let _ = ready.take_messages();
+
+    if !ready.snapshot().is_empty() {
+        // This is a snapshot, we need to apply the snapshot at first.
+        storage.wl().apply_snapshot(ready.snapshot().clone()).unwrap();
+    }
+
+    // Persistent raft logs. It's necessary because in `Node::advance` we stabilize
+    // raft logs to the latest position.
+    storage.wl().append(ready.entries()).unwrap();
+
+    if let Some(hs) = ready.hs() {
+        // Raft HardState changed, and we need to persist it.
+        storage.wl().set_hardstate(hs.clone());
+    }
+
+    // Messages like vote and append responses can only be sent once the state
+    // above is persisted.
+    let _ = ready.take_persisted_messages();
+
+    for entry in ready.take_committed_entries() {
+        // For conf change messages, make them effective.
+        let cs = match entry.entry_type() {
+            EntryType::EntryNormal => None,
+            EntryType::EntryConfChange => {
+                let cc = ConfChange::decode(entry.data.as_slice()).unwrap();
+                Some(node.apply_conf_change(&cc).unwrap())
+            }
+            EntryType::EntryConfChangeV2 => {
+                let cc = ConfChangeV2::decode(entry.data.as_slice()).unwrap();
+                Some(node.apply_conf_change(&cc).unwrap())
+            }
+        };
+        if let Some(cs) = cs {
+            storage.wl().set_conf_state(cs);
+            continue;
+        }
+        if entry.data.is_empty() {
+            // From new elected leaders.
+            continue;
+        }
+        if let Some(cb) = cbs.remove(&entry.data[0]) {
+            cb();
+        }
+    }
+
+    // Advance the Raft.
+    node.advance(ready);
+}
+
 async fn send_propose(logger: Logger, sender: mpsc::UnboundedSender<Msg>) {
     let _ = tokio::task::spawn(async move {
         // Wait some time and send the request to the Raft.
@@ -1,12 +1,169 @@
 use crate::config::Config;
-use crate::raft::Raft;
-use crate::storage::Storage;
+use crate::raft::{Raft, StateRole};
+use crate::read_only::ReadState;
+use crate::storage::{GetEntriesContext, Storage};
 use anyhow::Result;
+use raftpb::proto::{ConfState, Entry, HardState, Message, MessageType, Snapshot};
+use raftpb::ConfChangeI;
 use slog::{info, Logger};
+use std::collections::VecDeque;
+use std::mem;
+
+/// Represents a Peer node in the cluster.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct SoftState {
+    /// The potential leader of the cluster.
+    pub leader_id: u64,
+    /// The soft role this node may take.
+    pub raft_state: StateRole,
+}
+
+/// Ready encapsulates the entries and messages that are ready to read,
+/// be saved to stable storage, committed or sent to other peers.
+#[derive(Default, Debug, PartialEq)]
+pub struct Ready {
+    number: u64,
+
+    ss: Option<SoftState>,
+
+    hs: Option<HardState>,
+
+    read_states: Vec<ReadState>,
+
+    entries: Vec<Entry>,
+
+    snapshot: Snapshot,
+
+    committed_entries: Vec<Entry>,
+
+    messages: Vec<Message>,
+
+    persisted_messages: Vec<Message>,
+}
+
+impl Ready {
+    /// The number of current Ready.
+    /// It is used for identifying the different Ready and ReadyRecord.
+    #[inline]
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// The current volatile state of a Node.
+    /// SoftState will be None if there is no update.
+    /// It is not required to consume or store SoftState.
+    #[inline]
+    pub fn ss(&self) -> Option<&SoftState> {
+        self.ss.as_ref()
+    }
+
+    /// The current state of a Node to be saved to stable storage.
+    /// HardState will be None state if there is no update.
+    #[inline]
+    pub fn hs(&self) -> Option<&HardState> {
+        self.hs.as_ref()
+    }
+
+    /// ReadStates specifies the state for read only query.
+    #[inline]
+    pub fn read_states(&self) -> &Vec<ReadState> {
+        &self.read_states
+    }
+
+    /// Take the ReadStates.
+    #[inline]
+    pub fn take_read_states(&mut self) -> Vec<ReadState> {
+        mem::take(&mut self.read_states)
+    }
+
+    /// Entries specifies entries to be saved to stable storage.
+    #[inline]
+    pub fn entries(&self) -> &Vec<Entry> {
+        &self.entries
+    }
+
+    /// Take the Entries.
+    #[inline]
+    pub fn take_entries(&mut self) -> Vec<Entry> {
+        mem::take(&mut self.entries)
+    }
+
+    /// Snapshot specifies the snapshot to be saved to stable storage.
+    #[inline]
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// CommittedEntries specifies entries to be committed to a
+    /// store/state-machine. These have previously been committed to stable
+    /// store.
+    #[inline]
+    pub fn committed_entries(&self) -> &Vec<Entry> {
+        &self.committed_entries
+    }
+
+    /// Take the CommitEntries.
+    #[inline]
+    pub fn take_committed_entries(&mut self) -> Vec<Entry> {
+        mem::take(&mut self.committed_entries)
+    }
+
+    /// Messages specifies outbound messages to be sent.
+    #[inline]
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Take the Messages.
+    #[inline]
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        mem::take(&mut self.messages)
+    }
+
+    /// Persisted Messages specifies outbound messages to be sent AFTER the HardState,
+    /// Entries and Snapshot are persisted to stable storage.
+    #[inline]
+    pub fn persisted_messages(&self) -> &[Message] {
+        &self.persisted_messages
+    }
+
+    /// Take the Persisted Messages.
+    #[inline]
+    pub fn take_persisted_messages(&mut self) -> Vec<Message> {
+        mem::take(&mut self.persisted_messages)
+    }
+}
+
+/// What a `Ready` handed out to the application asks it to do, kept so that
+/// `advance` does not depend on what the application took out of the `Ready`.
+#[derive(Default, Debug)]
+struct ReadyRecord {
+    number: u64,
+    // (index, term) of the last entry from the entries in Ready
+    last_log: Option<(u64, u64)>,
+    // (index, term) of the snapshot in Ready
+    snapshot: Option<(u64, u64)>,
+    // index of the last entry from the committed entries in Ready
+    applied: Option<u64>,
+}
+
+/// The status of a snapshot sent to a peer, as reported by the application.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SnapshotStatus {
+    /// Represents that the snapshot is finished being created.
+    Finish,
+    /// Indicates that the snapshot failed to build or is not ready.
+    Failure,
+}
 
 /// Node server
 pub struct Node<T: Storage> {
     pub raft: Raft<T>,
+    prev_ss: SoftState,
+    prev_hs: HardState,
+    // Current max number of Record and ReadyRecord.
+    max_number: u64,
+    records: VecDeque<ReadyRecord>,
 }
 
 impl<T: Storage> Node<T> {
@@ -14,7 +171,15 @@ impl<T: Storage> Node<T> {
     /// Create a new RawNode given some [`Config`].
     pub fn new(config: &Config, storage: T, logger: &Logger) -> Result<Self> {
         let r = Raft::new(config, storage, logger)?;
-        let rn = Node { raft: r };
+        let mut rn = Node {
+            raft: r,
+            prev_ss: Default::default(),
+            prev_hs: Default::default(),
+            max_number: 0,
+            records: VecDeque::new(),
+        };
+        rn.prev_hs = rn.raft.hard_state();
+        rn.prev_ss = rn.raft.soft_state();
         info!(
             rn.raft.logger,
             "RawNode created with id {id}.",
@@ -23,15 +188,457 @@ impl<T: Storage> Node<T> {
         Ok(rn)
     }
 
+    /// Given an index, can determine if there is a ready state from that time.
     pub fn has_ready(&self) -> bool {
         let raft = &self.raft;
         if !raft.msg.is_empty() {
             return true;
         }
+
+        if raft.soft_state() != self.prev_ss {
+            return true;
+        }
+
+        let hs = raft.hard_state();
+        if hs != HardState::default() && hs != self.prev_hs {
+            return true;
+        }
+
+        if !raft.read_states.is_empty() {
+            return true;
+        }
+
+        if !raft.raft_log.unstable_entries().is_empty() {
+            return true;
+        }
+
+        if raft.raft_log.unstable_snapshot().is_some() {
+            return true;
+        }
+
+        if raft.raft_log.has_next_entries() {
+            return true;
+        }
+
         false
     }
 
+    /// Returns the outstanding work that the application needs to handle.
+    ///
+    /// This includes appending and applying entries or a snapshot, updating the HardState,
+    /// and sending messages. The returned `Ready` *MUST* be handled and subsequently
+    /// passed back via `advance` or its families. Before that, *DO NOT* call any function
+    /// like `step`, `propose`, etc.
+    pub fn ready(&mut self) -> Ready {
+        let raft = &mut self.raft;
+        self.max_number += 1;
+        let mut rd = Ready {
+            number: self.max_number,
+            ..Default::default()
+        };
+        let mut record = ReadyRecord {
+            number: self.max_number,
+            ..Default::default()
+        };
+
+        let ss = raft.soft_state();
+        if ss != self.prev_ss {
+            rd.ss = Some(ss);
+        }
+
+        let hs = raft.hard_state();
+        if hs != self.prev_hs {
+            rd.hs = Some(hs);
+        }
+
+        rd.read_states = mem::take(&mut raft.read_states);
+
+        if let Some(snapshot) = raft.raft_log.unstable_snapshot() {
+            rd.snapshot = snapshot.clone();
+            let meta = snapshot.metadata.clone().unwrap_or_default();
+            record.snapshot = Some((meta.index, meta.term));
+        }
+
+        rd.entries = raft.raft_log.unstable_entries().to_vec();
+        if let Some(e) = rd.entries.last() {
+            record.last_log = Some((e.index, e.term));
+        }
+
+        // Committed entries can be applied before they are persisted locally, a
+        // quorum has persisted them already.
+        if let Some(ents) = raft.raft_log.next_entries(None) {
+            record.applied = ents.last().map(|e| e.index);
+            rd.committed_entries = ents;
+        }
+
+        // The leader can send messages to its followers in parallel with writing
+        // to its own disk. Anything else a node says, like vote and append
+        // responses, promises that its state is durable.
+        if raft.state == StateRole::Leader {
+            rd.messages = mem::take(&mut raft.msg);
+        } else {
+            rd.persisted_messages = mem::take(&mut raft.msg);
+        }
+
+        self.records.push_back(record);
+        rd
+    }
+
+    fn commit_ready(&mut self, rd: Ready) {
+        let record = self
+            .records
+            .back()
+            .expect("advance must be called after ready");
+        assert_eq!(record.number, rd.number);
+        if let Some(ss) = rd.ss {
+            self.prev_ss = ss;
+        }
+        if let Some(hs) = rd.hs {
+            self.prev_hs = hs;
+        }
+
+        let (snapshot, last_log, applied) = (record.snapshot, record.last_log, record.applied);
+        let raft_log = &mut self.raft.raft_log;
+        if let Some((index, _)) = snapshot {
+            raft_log.stable_snap_to(index);
+            // The application installs the snapshot as its state machine.
+            raft_log.applied_to(index);
+        }
+        if let Some((index, term)) = last_log {
+            raft_log.stable_to(index, term);
+        }
+        if let Some(applied) = applied {
+            self.raft.commit_apply(applied);
+        }
+    }
+
+    /// Advance notifies the Node that the application has applied and saved progress in the
+    /// last Ready results.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is not called right after a `ready`.
+    pub fn advance(&mut self, rd: Ready) {
+        let number = rd.number;
+        self.commit_ready(rd);
+        self.on_persist_ready(number);
+    }
+
+    /// Same as `advance` except that it does not mark the entries, snapshot and
+    /// HardState of the Ready as persisted. The application must write them out,
+    /// send the persisted messages afterwards and then call `on_persist_ready`.
+    ///
+    /// The entries must be readable through `Storage` once this returns.
+    pub fn advance_append_async(&mut self, rd: Ready) {
+        self.commit_ready(rd);
+    }
+
+    /// Notifies that the ready of this number has been persisted, along with every
+    /// ready before it.
+    ///
+    /// Since Ready must be persisted in order, calling this function implicitly means
+    /// all readies with numbers smaller than this one have been persisted.
+    pub fn on_persist_ready(&mut self, number: u64) {
+        let (mut index, mut term) = (0, 0);
+        let mut snap_index = 0;
+        while let Some(record) = self.records.front() {
+            if record.number > number {
+                break;
+            }
+            let record = self.records.pop_front().unwrap();
+
+            if let Some((i, _)) = record.snapshot {
+                snap_index = i;
+                index = 0;
+                term = 0;
+            }
+
+            if let Some((i, t)) = record.last_log {
+                index = i;
+                term = t;
+            }
+        }
+        if snap_index != 0 {
+            self.raft.on_persist_snap(snap_index);
+        }
+        if index != 0 {
+            self.raft.on_persist_entries(index, term);
+        }
+    }
+
     pub fn tick(&mut self) -> bool {
         self.raft.tick()
     }
+
+    /// Propose proposes data be appended to the raft log.
+    pub fn propose(&mut self, context: Vec<u8>, data: Vec<u8>) -> Result<()> {
+        self.raft.propose(context, data)
+    }
+
+    /// ProposeConfChange proposes a config change. Only one change may be pending
+    /// at a time; a proposal made before the previous one was applied is dropped.
+    ///
+    /// If the node enters joint state with `auto_leave` set to true, it leaves it by
+    /// itself once the change is applied. Otherwise it's the caller's responsibility
+    /// to propose an empty `ConfChangeV2` to leave the joint state.
+    pub fn propose_conf_change(&mut self, context: Vec<u8>, cc: impl ConfChangeI) -> Result<()> {
+        self.raft.propose_conf_change(context, cc)
+    }
+
+    /// Applies a config change to the local node. The app must call this when it
+    /// applies a configuration change, except when it decides to reject the
+    /// configuration change, in which case no call must take place.
+    pub fn apply_conf_change(&mut self, cc: &impl ConfChangeI) -> Result<ConfState> {
+        self.raft.apply_conf_change(cc)
+    }
+
+    /// Notifies that the entries storage was fetching asynchronously for `context`
+    /// are available, so that the append waiting for them is sent.
+    pub fn on_entries_fetched(&mut self, context: GetEntriesContext) {
+        self.raft.on_entries_fetched(context)
+    }
+
+    /// TransferLeader tries to transfer leadership to the given transferee.
+    pub fn transfer_leader(&mut self, transferee: u64) {
+        let mut m = Message {
+            from: transferee,
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgTransferLeader);
+        let _ = self.raft.step(m);
+    }
+
+    /// ReportUnreachable reports the given node is not reachable for the last send.
+    pub fn report_unreachable(&mut self, id: u64) {
+        let mut m = Message {
+            from: id,
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgUnreachable);
+        // we don't care if it is ok actually
+        let _ = self.raft.step(m);
+    }
+
+    /// ReportSnapshot reports the status of the snapshot sent to the given peer.
+    pub fn report_snapshot(&mut self, id: u64, status: SnapshotStatus) {
+        let mut m = Message {
+            from: id,
+            reject: status == SnapshotStatus::Failure,
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgSnapStatus);
+        // we don't care if it is ok actually
+        let _ = self.raft.step(m);
+    }
+
+    /// Request a snapshot from a leader.
+    /// The snapshot's index must be greater or equal to the current last index.
+    pub fn request_snapshot(&mut self) -> Result<()> {
+        self.raft.request_snapshot()
+    }
+
+    /// ReadIndex requests a read state. The read state will be set in ready.
+    /// Read State has a read index. Once the application advances further than the read
+    /// index, any linearizable read requests issued before the read request can be
+    /// processed safely. The read state will have the same rctx attached.
+    pub fn read_index(&mut self, rctx: Vec<u8>) {
+        let mut m = Message {
+            entries: vec![Entry {
+                data: rctx,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgReadIndex);
+        let _ = self.raft.step(m);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+    use crate::tracker::state::ProgressState;
+    use raftpb::proto::ConfState;
+    use slog::o;
+
+    fn new_test_node(id: u64, voters: Vec<u64>) -> (Node<MemStorage>, MemStorage) {
+        let storage = MemStorage::new_with_conf_state(ConfState {
+            voters,
+            ..Default::default()
+        });
+        let conf = Config {
+            id,
+            heartbeat_tick: 1,
+            election_tick: 10,
+            min_election_tick: 10,
+            max_election_tick: 20,
+            check_quorum: false,
+            ..Default::default()
+        };
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let node = Node::new(&conf, storage.clone(), &logger).unwrap();
+        (node, storage)
+    }
+
+    #[test]
+    fn test_ready_after_campaign() {
+        let (mut node, storage) = new_test_node(1, vec![1]);
+        assert!(!node.has_ready());
+
+        node.raft.become_candidate();
+        node.raft.become_leader();
+        node.propose(vec![], b"foo".to_vec()).unwrap();
+        assert!(node.has_ready());
+
+        let rd = node.ready();
+        assert_eq!(
+            rd.ss(),
+            Some(&SoftState {
+                leader_id: 1,
+                raft_state: StateRole::Leader
+            })
+        );
+        assert_eq!(rd.hs().map(|hs| hs.term), Some(1));
+        // The leader's empty entry followed by the proposal.
+        assert_eq!(rd.entries().len(), 2);
+        assert!(rd.committed_entries().is_empty());
+
+        storage.wl().append(rd.entries()).unwrap();
+        storage.wl().set_hardstate(rd.hs().unwrap().clone());
+        node.advance(rd);
+        assert!(node.raft.raft_log.unstable_entries().is_empty());
+
+        // Persisting the entries on the only voter commits them.
+        let rd = node.ready();
+        assert_eq!(rd.hs().map(|hs| hs.commit), Some(2));
+        assert_eq!(rd.committed_entries().len(), 2);
+        storage.wl().set_hardstate(rd.hs().unwrap().clone());
+        node.advance(rd);
+        assert!(!node.has_ready());
+    }
+
+    #[test]
+    fn test_ready_committed_entries() {
+        let (mut node, storage) = new_test_node(2, vec![1, 2]);
+
+        let mut m = Message {
+            to: 2,
+            from: 1,
+            term: 1,
+            commit: 1,
+            entries: vec![Entry {
+                index: 1,
+                term: 1,
+                data: b"foo".to_vec(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgAppend);
+        node.raft.step(m).unwrap();
+
+        let mut rd = node.ready();
+        assert_eq!(rd.entries().len(), 1);
+        assert_eq!(rd.committed_entries().len(), 1);
+        assert_eq!(rd.hs().map(|hs| hs.commit), Some(1));
+        let msgs = rd.take_persisted_messages();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].msg_type(), MessageType::MsgAppendResponse);
+
+        storage.wl().append(rd.entries()).unwrap();
+        node.advance(rd);
+        assert_eq!(node.raft.raft_log.persisted, 1);
+        assert_eq!(node.raft.raft_log.applied, 1);
+        assert!(!node.has_ready());
+    }
+
+    #[test]
+    fn test_async_persist_follower() {
+        let (mut node, storage) = new_test_node(2, vec![1, 2]);
+
+        let mut m = Message {
+            to: 2,
+            from: 1,
+            term: 1,
+            entries: vec![Entry {
+                index: 1,
+                term: 1,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgAppend);
+        node.raft.step(m).unwrap();
+
+        let mut rd = node.ready();
+        assert_eq!(rd.number(), 1);
+        // The append response promises the entry is durable.
+        assert!(rd.messages().is_empty());
+        let msgs = rd.take_persisted_messages();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].msg_type(), MessageType::MsgAppendResponse);
+
+        storage.wl().append(rd.entries()).unwrap();
+        node.advance_append_async(rd);
+        assert!(node.raft.raft_log.unstable_entries().is_empty());
+        assert_eq!(node.raft.raft_log.persisted, 0);
+        assert!(!node.has_ready());
+
+        node.on_persist_ready(1);
+        assert_eq!(node.raft.raft_log.persisted, 1);
+    }
+
+    #[test]
+    fn test_async_persist_leader() {
+        let (mut node, storage) = new_test_node(1, vec![1, 2]);
+        node.raft.become_candidate();
+        node.raft.become_leader();
+        node.propose(vec![], b"foo".to_vec()).unwrap();
+
+        let rd = node.ready();
+        // Appends go out before the leader's own write is durable.
+        assert_eq!(rd.messages().len(), 1);
+        assert_eq!(rd.messages()[0].msg_type(), MessageType::MsgAppend);
+        storage.wl().append(rd.entries()).unwrap();
+        let number = rd.number();
+        node.advance_append_async(rd);
+        assert_eq!(node.raft.prs().get(1).unwrap().matched, 0);
+
+        // A second ready is handed out while the first is still being written.
+        node.propose(vec![], b"bar".to_vec()).unwrap();
+        let rd = node.ready();
+        storage.wl().append(rd.entries()).unwrap();
+        node.advance_append_async(rd);
+
+        node.on_persist_ready(number);
+        assert_eq!(node.raft.prs().get(1).unwrap().matched, 2);
+        node.on_persist_ready(number + 1);
+        assert_eq!(node.raft.prs().get(1).unwrap().matched, 3);
+    }
+
+    #[test]
+    fn test_report_unreachable() {
+        let (mut node, _) = new_test_node(1, vec![1, 2]);
+        node.raft.become_candidate();
+        node.raft.become_leader();
+        let mut resp = Message {
+            from: 2,
+            to: 1,
+            term: node.raft.term,
+            index: 1,
+            ..Default::default()
+        };
+        resp.set_msg_type(MessageType::MsgAppendResponse);
+        node.raft.step(resp).unwrap();
+        assert_eq!(
+            node.raft.prs().get(2).unwrap().state,
+            ProgressState::Replicate
+        );
+
+        node.report_unreachable(2);
+        let pr = node.raft.prs().get(2).unwrap();
+        assert_eq!(pr.state, ProgressState::Probe);
+        assert_eq!(pr.next_idx, 2);
+    }
 }
@@ -94,4 +94,51 @@ impl Configuration {
         }
     }
 
+    /// Returns the largest committed index for the given joint quorum. An index is
+    /// jointly committed if it is committed in both constituent majorities.
+    pub fn committed_index(&self, l: &impl AckedIndexer) -> Index {
+        let i = self.incoming.committed_index(l);
+        let o = self.outgoing.committed_index(l);
+        if i.index < o.index {
+            i
+        } else {
+            o
+        }
+    }
+
+    /// Returns true if (and only if) there is only one voting member
+    /// (i.e. the leader) in the current configuration.
+    pub fn is_singleton(&self) -> bool {
+        self.outgoing.voters.is_empty() && self.incoming.voters.len() == 1
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acked(ids: &[(u64, u64)]) -> AckIndexer {
+        ids.iter()
+            .map(|&(id, index)| (id, Index { index, group_id: 0 }))
+            .collect()
+    }
+
+    #[test]
+    fn test_committed_index() {
+        let mut c = Configuration::with_capacity(3);
+        assert_eq!(c.committed_index(&acked(&[])).index, u64::MAX);
+
+        c.incoming.voters.extend([1, 2, 3]);
+        // The median of the acked indexes, missing voters count as 0.
+        assert_eq!(c.committed_index(&acked(&[(1, 5), (2, 3), (3, 1)])).index, 3);
+        assert_eq!(c.committed_index(&acked(&[(1, 5)])).index, 0);
+
+        // A joint config only commits what both majorities have.
+        c.outgoing.voters.extend([3, 4, 5]);
+        let l = acked(&[(1, 5), (2, 3), (3, 1), (4, 2), (5, 2)]);
+        assert_eq!(c.incoming.committed_index(&l).index, 3);
+        assert_eq!(c.outgoing.committed_index(&l).index, 2);
+        assert_eq!(c.committed_index(&l).index, 2);
+    }
 }
@@ -1,6 +1,7 @@
 use std::collections::HashSet;
 use std::fmt::Formatter;
 
+use crate::quorum::joint::{AckedIndexer, Index};
 use crate::raft::VoteResult;
 
 /// A set of IDs that uses majority quorums to make decisions.
@@ -35,6 +36,33 @@ impl Configuration {
     pub fn with_capacity(voters: usize) -> Configuration {
         Configuration { voters: HashSet::with_capacity(voters) }
     }
+
+    /// Computes the committed index from those supplied via the
+    /// provided AckedIndexer (for the active config).
+    ///
+    /// The committed index is the largest index acked by a quorum, i.e. the
+    /// median of the voters' acked indexes. Voters missing from the indexer
+    /// count as having acked nothing.
+    ///
+    /// By convention an empty configuration returns `u64::MAX`, so that a
+    /// half-populated joint config behaves like a majority config.
+    pub fn committed_index(&self, l: &impl AckedIndexer) -> Index {
+        if self.voters.is_empty() {
+            return Index {
+                index: u64::MAX,
+                group_id: 0,
+            };
+        }
+
+        let mut matched: Vec<Index> = self
+            .voters
+            .iter()
+            .map(|id| l.acked_index(*id).unwrap_or_default())
+            .collect();
+        // Reverse sort.
+        matched.sort_by_key(|i| std::cmp::Reverse(i.index));
+        matched[majority(matched.len()) - 1]
+    }
     /// Takes a mapping of voters to yes/no (true/false) votes and returns
     /// a result indicating whether the vote is pending (i.e. neither a quorum of
     /// yes/no has been reached), won (a quorum of yes has been reached), or lost (a
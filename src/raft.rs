@@ -1,16 +1,34 @@
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, Result};
 use rand::{self, Rng};
-use slog::{debug, info, Logger};
+use slog::{debug, error, info, warn, Logger};
+use std::cmp;
+use std::mem;
 use std::ops::{Deref, DerefMut};
 
-use crate::{confchange, config::Config, tracker::ProgressTracker};
-use crate::storage::{RaftLog, Storage};
-use raftpb::proto::{Message, MessageType};
+use crate::tracker::{state::ProgressState, ProgressTracker};
+use crate::errors::{Error, StorageError};
+use crate::{confchange, config::Config};
+use crate::node::SoftState;
+use crate::read_only::{ReadOnly, ReadOnlyOption, ReadState};
+use crate::storage::{GetEntriesContext, GetEntriesFor, RaftLog, Storage};
+use crate::confchange::changer::{joint, Changer};
+use prost::Message as ProstMessage;
+use raftpb::proto::{
+    ConfChange, ConfChangeV2, ConfState, Entry, EntryType, HardState, Message, MessageType,
+    Snapshot,
+};
+use raftpb::ConfChangeI;
 
 /// A constant represents invalid id of raft.
 pub const INVALID_ID: u64 = 0;
+/// A constant represents invalid index of raft log.
+pub const INVALID_INDEX: u64 = 0;
 
 #[doc(hidden)]
+// CAMPAIGN_PRE_ELECTION represents the first phase of a normal election when
+// Config.pre_vote is true.
+#[doc(hidden)]
+pub const CAMPAIGN_PRE_ELECTION: &[u8] = b"CampaignPreElection";
 // CAMPAIGN_ELECTION represents a normal (time-based) election (the second phase
 // of the election when Config.pre_vote is true).
 #[doc(hidden)]
@@ -28,12 +46,21 @@ pub enum VoteResult {
     Lost,
 }
 
-fn new_message(to: u64, field_type: MessageType, from: Option<u64>) -> Message {
-    let mut m = Message::default();
-    m.to = to;
-    if let Some(id) = from {
-        m.from = id;
+/// Maps vote and pre_vote message types to their correspond responses.
+pub fn vote_resp_msg_type(t: MessageType) -> MessageType {
+    match t {
+        MessageType::MsgRequestVote => MessageType::MsgRequestVoteResponse,
+        MessageType::MsgRequestPreVote => MessageType::MsgRequestPreVoteResponse,
+        _ => panic!("Not a vote message: {:?}", t),
     }
+}
+
+fn new_message(to: u64, field_type: MessageType, from: Option<u64>) -> Message {
+    let mut m = Message {
+        to,
+        from: from.unwrap_or_default(),
+        ..Default::default()
+    };
     m.set_msg_type(field_type);
     m
 }
@@ -55,6 +82,29 @@ pub struct RaftCore<T: Storage> {
     /// leader id
     pub leader_id: u64,
 
+    /// ID of the leader transfer target when its value is not None.
+    pub lead_transferee: Option<u64>,
+
+    /// The read states waiting to be handed to the application through `Ready`.
+    pub read_states: Vec<ReadState>,
+
+    /// The read-only requests the leader is confirming leadership for.
+    pub read_only: ReadOnly,
+
+    /// Only one conf change may be pending (in the log, but not yet
+    /// applied) at a time. This is enforced via `pending_conf_index`, which
+    /// is set to a value >= the log index of the latest pending
+    /// configuration change (if any). Config changes are only allowed to
+    /// be proposed if the leader's applied index is greater than this
+    /// value.
+    pub pending_conf_index: u64,
+
+    /// The index the follower asked the leader to snapshot at, or INVALID_INDEX.
+    pub pending_request_snapshot: u64,
+
+    /// Read requests received before the leader committed an entry in its term.
+    pending_read_index_messages: Vec<Message>,
+
     /// if it doesn't receive message from leader
     /// it will timeout
     election_timeout: usize,
@@ -63,6 +113,16 @@ pub struct RaftCore<T: Storage> {
     /// Whether to check the quorum
     pub check_quorum: bool,
 
+    /// Enable the prevote algorithm.
+    ///
+    /// This enables a pre-election vote round on Candidates prior to disrupting the cluster.
+    ///
+    /// Enable this if greater cluster stability is preferred over faster elections.
+    pub pre_vote: bool,
+
+    /// The maximum total size of the entries carried by one append message.
+    pub max_msg_size: u64,
+
     /// Randomize election timeout
     randomized_election_timeout: usize,
     min_election_timeout: usize,
@@ -77,20 +137,15 @@ pub struct RaftCore<T: Storage> {
     pub logger: Logger,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub enum StateRole {
+    #[default]
     Follower,
     Candidate,
     Leader,
     PreCandidate,
 }
 
-impl Default for StateRole {
-    fn default() -> Self {
-        Self::Follower
-    }
-}
-
 pub struct Raft<T: Storage> {
     prs: ProgressTracker,
     pub r: RaftCore<T>,
@@ -131,7 +186,7 @@ impl<T: Storage> Raft<T> {
         let conf_state = &raft_state.conf_state;
 
         let mut r = Raft {
-            prs: Default::default(),
+            prs: ProgressTracker::new(conf.max_inflight_msgs),
             r: RaftCore {
                 id: conf.id,
                 term: Default::default(),
@@ -139,6 +194,12 @@ impl<T: Storage> Raft<T> {
                 raft_log: RaftLog::new(storage),
                 state: StateRole::default(),
                 leader_id: Default::default(),
+                lead_transferee: None,
+                read_states: Default::default(),
+                read_only: ReadOnly::new(conf.read_only_option),
+                pending_read_index_messages: Default::default(),
+                pending_request_snapshot: INVALID_INDEX,
+                pending_conf_index: Default::default(),
                 election_timeout: conf.election_tick,
                 heartbeat_timeout: conf.heartbeat_tick,
                 randomized_election_timeout: Default::default(),
@@ -148,27 +209,109 @@ impl<T: Storage> Raft<T> {
                 election_elapsed: Default::default(),
                 heartbeat_elapsed: Default::default(),
                 check_quorum: conf.check_quorum,
+                pre_vote: conf.pre_vote,
+                max_msg_size: conf.max_size_per_msg,
             },
             msg: Default::default(),
         };
         confchange::restore::restore(&mut r.prs, r.r.raft_log.last_index(), conf_state)?;
+        if raft_state.hard_state != HardState::default() {
+            r.load_state(&raft_state.hard_state)?;
+        }
+        if conf.applied > 0 {
+            let (first, committed) = (r.raft_log.first_index(), r.raft_log.committed);
+            if conf.applied + 1 < first || conf.applied > committed {
+                return Err(anyhow!(
+                    "{} applied {} is out of range [{}, {}]",
+                    r.id,
+                    conf.applied,
+                    first - 1,
+                    committed
+                ));
+            }
+            r.commit_apply(conf.applied);
+        }
         r.become_follower(r.term, INVALID_ID);
         info!(
             r.logger,
             "newRaft";
             "term" => r.term,
+            "commit" => r.raft_log.committed,
+            "applied" => r.raft_log.applied,
+            "last index" => r.raft_log.last_index(),
+            "last term" => r.raft_log.last_term(),
+            "peers" => ?r.prs.conf().voters,
         );
         Ok(r)
     }
 
+    /// Restores the term, vote and commit index persisted before a restart.
+    /// The commit index must lie within the log.
+    pub fn load_state(&mut self, hs: &HardState) -> Result<()> {
+        if hs.commit < self.raft_log.committed || hs.commit > self.raft_log.last_index() {
+            return Err(anyhow!(
+                "{} state.commit {} is out of range [{}, {}]",
+                self.id,
+                hs.commit,
+                self.raft_log.committed,
+                self.raft_log.last_index()
+            ));
+        }
+        self.raft_log.committed = hs.commit;
+        self.term = hs.term;
+        self.vote = hs.vote;
+        Ok(())
+    }
+
+    /// Returns whether the current raft is in the voter set and may campaign.
+    /// Learners and nodes removed from the configuration are not promotable.
+    pub fn promotable(&self) -> bool {
+        self.prs.conf().voters.contains(self.id)
+    }
+
+    /// Returns a read-only reference to the progress set.
+    pub fn prs(&self) -> &ProgressTracker {
+        &self.prs
+    }
+
+    /// Returns a value representing the softstate at the time of calling.
+    pub fn soft_state(&self) -> SoftState {
+        SoftState {
+            leader_id: self.leader_id,
+            raft_state: self.state,
+        }
+    }
+
+    /// Returns a value representing the hardstate at the time of calling.
+    pub fn hard_state(&self) -> HardState {
+        HardState {
+            term: self.term,
+            vote: self.vote,
+            commit: self.raft_log.committed,
+        }
+    }
+
     pub fn reset_term(&mut self, term: u64) {
         if self.term != term {
             self.term = term;
             self.vote = INVALID_ID;
         }
         self.leader_id = INVALID_ID;
+        self.lead_transferee = None;
+        self.pending_conf_index = 0;
+        self.read_only = ReadOnly::new(self.read_only.option);
+        self.pending_read_index_messages.clear();
         self.prs.reset_votes();
-        self.randomized_election_timeout()
+        self.randomized_election_timeout();
+
+        let (last_index, persisted) = (self.raft_log.last_index(), self.raft_log.persisted);
+        let self_id = self.id;
+        for (&id, pr) in self.prs.iter_mut() {
+            pr.reset(last_index + 1);
+            if id == self_id {
+                pr.matched = persisted;
+            }
+        }
     }
 
     pub fn randomized_election_timeout(&mut self) {
@@ -204,7 +347,29 @@ impl<T: Storage> Raft<T> {
         self.reset_term(term);
         self.leader_id = self.id;
         self.state = StateRole::Leader;
-        
+
+        // Followers enter replicate mode when they've been successfully probed
+        // (perhaps after having received a snapshot as a result). The leader is
+        // trivially in this state.
+        let self_id = self.id;
+        if let Some(pr) = self.prs.get_mut(self_id) {
+            pr.become_replicate();
+        }
+
+        // The leader's own entries may still be in flight to storage; they only
+        // count towards its match index once persisted, see on_persist_entries.
+
+        // Conservatively set the pending_conf_index to the last index in the
+        // log. There may or may not be a pending config change, but it's
+        // safe to delay any future proposals until we commit all our
+        // pending log entries, and scanning the entire tail of the log
+        // could be expensive.
+        self.pending_conf_index = self.raft_log.last_index();
+
+        // Append an empty entry at the new term so that entries from earlier terms
+        // get committed along with it, see commit_to_current_term.
+        self.append_entry(&mut [Entry::default()]);
+
         // When becoming leader, reset heartbeat timer
         self.heartbeat_elapsed = 0;
 
@@ -215,6 +380,32 @@ impl<T: Storage> Raft<T> {
         );
     }
 
+    /// Converts this node to a pre-candidate
+    ///
+    /// # Panics
+    ///
+    /// Panics if a leader already exists.
+    pub fn become_pre_candidate(&mut self) {
+        assert_ne!(
+            self.state,
+            StateRole::Leader,
+            "Can not transitted Leader -> Pre-candidate"
+        );
+        // Becoming a pre-candidate changes our state,
+        // but doesn't change anything else. In particular it does not increase
+        // self.term or change self.vote.
+        self.state = StateRole::PreCandidate;
+        self.prs.reset_votes();
+        // If a network partition happens, and leader is in minority partition,
+        // it will step down, and become follower without notifying others.
+        self.leader_id = INVALID_ID;
+        info!(
+            self.logger,
+            "became pre-candidate at term {term}",
+            term = self.term;
+        );
+    }
+
     pub fn become_candidate(&mut self) {
         assert_ne!(
             self.state,
@@ -233,6 +424,168 @@ impl<T: Storage> Raft<T> {
         );
     }
 
+    /// Proposes data be appended to the raft log. Only the leader appends the entry, a
+    /// follower forwards it to the leader it knows about.
+    pub fn propose(&mut self, context: Vec<u8>, data: Vec<u8>) -> Result<()> {
+        let mut m = new_message(INVALID_ID, MessageType::MsgPropose, Some(self.id));
+        let e = Entry {
+            data,
+            context,
+            ..Default::default()
+        };
+        m.entries = vec![e];
+        self.step(m)
+    }
+
+    /// Proposes a configuration change. A `ConfChange` is encoded as an
+    /// `EntryConfChange`, a `ConfChangeV2` as an `EntryConfChangeV2`. It is dropped
+    /// if another change is still waiting to be applied.
+    pub fn propose_conf_change(&mut self, context: Vec<u8>, cc: impl ConfChangeI) -> Result<()> {
+        let (data, ty) = if let Some(cc) = cc.as_v1() {
+            (cc.encode_to_vec(), EntryType::EntryConfChange)
+        } else {
+            (cc.as_v2().encode_to_vec(), EntryType::EntryConfChangeV2)
+        };
+        let mut m = new_message(INVALID_ID, MessageType::MsgPropose, Some(self.id));
+        let mut e = Entry {
+            data,
+            context,
+            ..Default::default()
+        };
+        e.set_entry_type(ty);
+        m.entries = vec![e];
+        self.step(m)
+    }
+
+    /// Applies a committed configuration change to the progress tracker and
+    /// returns the resulting `ConfState` for the application to persist.
+    ///
+    /// A zero `ConfChangeV2` leaves the joint configuration, changes that need
+    /// joint consensus enter it and a single change is applied directly.
+    pub fn apply_conf_change(&mut self, cc: &impl ConfChangeI) -> Result<ConfState> {
+        let cc = cc.as_v2();
+        let mut changer = Changer::new(&mut self.prs);
+        let (cfg, changes) = if cc.leave_joint() {
+            changer.leave_joint()?
+        } else if let Some(auto_leave) = cc.enter_joint() {
+            changer.enter_joint(auto_leave, &cc.changes)?
+        } else {
+            changer.simple(&cc.changes)?
+        };
+        let last_index = self.raft_log.last_index();
+        self.prs.apply_conf(cfg, changes, last_index);
+        Ok(self.post_conf_change())
+    }
+
+    /// Notifies that entries up to `applied` have been applied to the state machine.
+    ///
+    /// When the applied entry entered a joint configuration with `auto_leave` set,
+    /// the leader proposes an empty `ConfChangeV2` to transition out of it.
+    pub fn commit_apply(&mut self, applied: u64) {
+        let old_applied = self.raft_log.applied;
+        self.raft_log.applied_to(applied);
+
+        // A leader that steps down before the enter entry is applied leaves this to
+        // its successor, which sees the joint config once it applies the entry.
+        if self.prs.conf().auto_leave
+            && old_applied <= self.pending_conf_index
+            && applied >= self.pending_conf_index
+            && self.state == StateRole::Leader
+        {
+            // If the current (and most recent, at least for this leader's term)
+            // configuration should be auto-left, initiate that now. Empty data
+            // decodes into an empty ConfChangeV2.
+            let mut entry = Entry::default();
+            entry.set_entry_type(EntryType::EntryConfChangeV2);
+            self.append_entry(&mut [entry]);
+            self.pending_conf_index = self.raft_log.last_index();
+            self.bcast_append();
+            info!(self.logger, "initiating automatic transition out of joint configuration"; "config" => ?self.prs.conf());
+        }
+    }
+
+    /// Updates the leader's replication after the configuration changed.
+    fn post_conf_change(&mut self) -> ConfState {
+        let cs = self.prs.conf_state();
+        // The remaining steps only make sense if this node is the leader and there
+        // are other nodes.
+        if self.state != StateRole::Leader || cs.voters.is_empty() {
+            return cs;
+        }
+        if self.maybe_commit() {
+            // If the configuration change means that more entries are committed now,
+            // broadcast/append to everyone in the updated config.
+            self.release_pending_read_index_messages();
+            self.bcast_append();
+        } else {
+            // Otherwise, still probe the newly added replicas; there's no reason to
+            // let them wait out a heartbeat interval (or the next incoming proposal).
+            let self_id = self.id;
+            for id in self.prs.ids() {
+                if id != self_id {
+                    self.maybe_send_append(id, false);
+                }
+            }
+        }
+        // If the lead_transferee was removed or demoted, abort the leadership transfer.
+        if let Some(lead_transferee) = self.lead_transferee {
+            if !self.prs.conf().voters.contains(lead_transferee) {
+                self.abort_leader_transfer();
+            }
+        }
+        cs
+    }
+
+    /// Returns true if the leader has committed an entry in its current term.
+    ///
+    /// Until then its commit index may lag behind entries committed by former
+    /// leaders, so it cannot serve read-only requests.
+    pub fn commit_to_current_term(&self) -> bool {
+        self.raft_log
+            .term(self.raft_log.committed)
+            .is_ok_and(|t| t == self.term)
+    }
+
+    /// Appends a slice of entries to the log at the current term.
+    /// The entries are updated to reflect the current term and their log positions.
+    fn append_entry(&mut self, es: &mut [Entry]) {
+        let li = self.raft_log.last_index();
+        for (i, e) in es.iter_mut().enumerate() {
+            e.term = self.term;
+            e.index = li + 1 + i as u64;
+        }
+        // Not update self's pr.matched until on_persist_entries
+        self.raft_log.append(es);
+    }
+
+    /// Notifies that these raft logs have been persisted.
+    pub fn on_persist_entries(&mut self, index: u64, term: u64) {
+        let update = self.raft_log.maybe_persist(index, term);
+        if update && self.state == StateRole::Leader {
+            let self_id = self.id;
+            if let Some(pr) = self.prs.get_mut(self_id) {
+                pr.maybe_update(index);
+            }
+            if self.maybe_commit() {
+                self.release_pending_read_index_messages();
+                self.bcast_append();
+            }
+        }
+    }
+
+    /// Checks whether a quorum has replicated entries past the commit index
+    /// and, if so, commits them. Returns true if the commit index changed.
+    fn maybe_commit(&mut self) -> bool {
+        let mci = self.prs.maximal_committed_index();
+        let term = self.term;
+        self.r.raft_log.maybe_commit(mci, term)
+    }
+
+    /// Notifies that the snapshot have been persisted.
+    pub fn on_persist_snap(&mut self, index: u64) {
+        self.raft_log.maybe_persist_snap(index);
+    }
+
     pub fn step(&mut self, msg: Message) -> Result<()> {
         if msg.term == 0 {
             // Local message
@@ -254,51 +607,101 @@ impl<T: Storage> Raft<T> {
                 }
             }
             
-            info!(
-                self.logger,
-                "received higher term from {from}",
-                from = msg.from;
-                "term" => self.term,
-                "msg_term" => msg.term,
-            );
-            if msg.msg_type() == MessageType::MsgHeartbeat {
-                self.become_follower(msg.term, msg.from);
+            if msg.msg_type() == MessageType::MsgRequestPreVote
+                || (msg.msg_type() == MessageType::MsgRequestPreVoteResponse && !msg.reject)
+            {
+                // For a pre-vote request:
+                // Never change our term in response to a pre-vote request.
+                //
+                // For a pre-vote response with pre-vote granted:
+                // We send pre-vote requests with a term in our future. If the
+                // pre-vote is granted, we will increment our term when we get a
+                // quorum. If it is not, the term comes from the node that
+                // rejected our vote so we should become a follower at the new
+                // term.
             } else {
-                self.become_follower(msg.term, INVALID_ID);
+                info!(
+                    self.logger,
+                    "received higher term from {from}",
+                    from = msg.from;
+                    "term" => self.term,
+                    "msg_term" => msg.term,
+                );
+                if msg.msg_type() == MessageType::MsgHeartbeat
+                    || msg.msg_type() == MessageType::MsgAppend
+                {
+                    self.become_follower(msg.term, msg.from);
+                } else {
+                    self.become_follower(msg.term, INVALID_ID);
+                }
             }
         } else if msg.term < self.term {
-            if (self.check_quorum || self.state == StateRole::Leader)
+            if (self.check_quorum || self.pre_vote || self.state == StateRole::Leader)
                 && (msg.msg_type() == MessageType::MsgHeartbeat
                 || msg.msg_type() == MessageType::MsgAppend)
             {
                 // Respond to old leader with our higher term to make them step down
-                let mut m = new_message(msg.from, MessageType::MsgAppendResponse, None);
+                let mut m = new_message(msg.from, MessageType::MsgAppendResponse, Some(self.id));
                 m.term = self.term;
                 self.r.send(m, &mut self.msg);
-                return Ok(());
-            } else {
-                return Ok(());
+            } else if msg.msg_type() == MessageType::MsgRequestPreVote {
+                // Before pre_vote enable, there may be a receiving candidate with higher term,
+                // but less log. After update to pre_vote, the cluster may deadlock if
+                // we drop messages with a lower term.
+                let mut m = new_message(msg.from, MessageType::MsgRequestPreVoteResponse, Some(self.id));
+                m.term = self.term;
+                m.reject = true;
+                self.r.send(m, &mut self.msg);
             }
+            return Ok(());
         }
 
         match msg.msg_type() {
             MessageType::MsgHup => self.hup(false),
             MessageType::MsgRequestVote | MessageType::MsgRequestPreVote => {
-                let can_vote = (self.vote == msg.from) || 
-                               (self.vote == INVALID_ID && self.leader_id == INVALID_ID);
-                if can_vote && self.raft_log.is_up_to_date(msg.index, msg.log_term) {
-                    self.election_elapsed = 0;
-                    self.vote = msg.from;
-                    let mut m = new_message(msg.from, MessageType::MsgRequestVoteResponse, Some(self.id));
-                    m.term = self.term;
+                // We can vote if this is a repeat of a vote we've already cast...
+                let can_vote = (self.vote == msg.from) ||
+                               // ...we haven't voted and we don't think there's a leader yet in this term...
+                               (self.vote == INVALID_ID && self.leader_id == INVALID_ID) ||
+                               // ...or this is a PreVote for a future term...
+                               (msg.msg_type() == MessageType::MsgRequestPreVote && msg.term > self.term);
+                let resp_type = vote_resp_msg_type(msg.msg_type());
+                // Learners are left out of elections, they never grant a vote.
+                if can_vote
+                    && self.promotable()
+                    && self.raft_log.is_up_to_date(msg.index, msg.log_term)
+                {
+                    // When responding to Msg{Pre,}Vote messages we include the term
+                    // from the message, not the local term. To see why consider the
+                    // case where a single node was previously partitioned away and
+                    // its local term is now out of date. If we include the local term
+                    // (recall that for pre-votes we don't update the local term), the
+                    // (pre-)candidate would ignore our vote response, since its term is
+                    // greater than ours.
+                    let mut m = new_message(msg.from, resp_type, Some(self.id));
+                    m.term = msg.term;
                     self.r.send(m, &mut self.msg);
+                    if msg.msg_type() == MessageType::MsgRequestVote {
+                        // Only record real votes.
+                        self.election_elapsed = 0;
+                        self.vote = msg.from;
+                    }
                 } else {
-                    let mut m = new_message(msg.from, MessageType::MsgRequestVoteResponse, Some(self.id));
+                    let mut m = new_message(msg.from, resp_type, Some(self.id));
                     m.term = self.term;
                     m.reject = true;
                     self.r.send(m, &mut self.msg);
                 }
             }
+            MessageType::MsgReadIndex if msg.entries.is_empty() => {
+                // The request context travels in the first entry, there is nothing to
+                // serve without it.
+                warn!(
+                    self.logger,
+                    "dropping MsgReadIndex from {from} without a request context",
+                    from = msg.from;
+                );
+            }
             _ => match self.state {
                 StateRole::Candidate | StateRole::PreCandidate => self.step_candidate(msg)?,
                 StateRole::Follower => self.step_follower(msg)?,
@@ -312,17 +715,39 @@ impl<T: Storage> Raft<T> {
         if self.state == StateRole::Leader {
             return;
         }
+        if !self.promotable() {
+            warn!(
+                self.logger,
+                "{id} is unpromotable and can not campaign",
+                id = self.id;
+            );
+            return;
+        }
         if transfer_leader {
             self.campaign(CAMPAIGN_TRANSFER);
+        } else if self.pre_vote {
+            self.campaign(CAMPAIGN_PRE_ELECTION);
         } else {
             self.campaign(CAMPAIGN_ELECTION);
         }
     }
 
-    fn campaign(&mut self, _campaign_type: &'static [u8]) {
-        self.become_candidate();
+    /// Campaign to attempt to become a leader.
+    ///
+    /// If prevote is enabled, this is handled as well.
+    fn campaign(&mut self, campaign_type: &'static [u8]) {
+        let (vote_msg, term) = if campaign_type == CAMPAIGN_PRE_ELECTION {
+            self.become_pre_candidate();
+            // Pre-vote RPCs are sent for next term before we've incremented self.term.
+            (MessageType::MsgRequestPreVote, self.term + 1)
+        } else {
+            self.become_candidate();
+            (MessageType::MsgRequestVote, self.term)
+        };
         let self_id = self.id;
-        if VoteResult::Won == self.poll(self_id, MessageType::MsgRequestVote, true) {
+        if VoteResult::Won == self.poll(self_id, vote_msg, true) {
+            // We won the election after voting for ourselves (which must mean that
+            // this is a single-node cluster).
             return;
         }
 
@@ -334,10 +759,13 @@ impl<T: Storage> Raft<T> {
             if id == self_id {
                 continue;
             }
-            let mut m = new_message(id, MessageType::MsgRequestVote, Some(self_id));
-            m.term = self.term;
+            let mut m = new_message(id, vote_msg, Some(self_id));
+            m.term = term;
             m.index = last_index;
             m.log_term = last_term;
+            if campaign_type == CAMPAIGN_TRANSFER {
+                m.context = campaign_type.to_vec();
+            }
             self.r.send(m, &mut self.msg);
         }
     }
@@ -353,7 +781,7 @@ impl<T: Storage> Raft<T> {
 
     fn tick_election(&mut self) -> bool {
         self.election_elapsed += 1;
-        if self.election_elapsed >= self.randomized_election_timeout {
+        if self.promotable() && self.election_elapsed >= self.randomized_election_timeout {
             self.election_elapsed = 0;
             let m = new_message(INVALID_ID, MessageType::MsgHup, Some(self.id));
             let _ = self.step(m);
@@ -373,6 +801,10 @@ impl<T: Storage> Raft<T> {
                 let m = new_message(INVALID_ID, MessageType::MsgCheckQuorum, Some(self.id));
                 let _ = self.step(m);
             }
+            // If current leader cannot transfer leadership in electionTimeout, it becomes leader again.
+            if self.state == StateRole::Leader && self.lead_transferee.is_some() {
+                self.abort_leader_transfer()
+            }
         }
 
         if self.state != StateRole::Leader {
@@ -393,7 +825,38 @@ impl<T: Storage> Raft<T> {
 
     fn step_candidate(&mut self, msg: Message) -> Result<()> {
         match msg.msg_type() {
+            MessageType::MsgPropose => {
+                info!(
+                    self.logger,
+                    "no leader at term {term}; dropping proposal",
+                    term = self.term;
+                );
+                return Err(Error::ProposalDropped.into());
+            }
+            MessageType::MsgAppend => {
+                // A leader already exists for this term.
+                self.become_follower(msg.term, msg.from);
+                self.handle_append_entries(&msg);
+            }
+            MessageType::MsgHeartbeat => {
+                self.become_follower(msg.term, msg.from);
+                self.handle_heartbeat(msg);
+            }
+            MessageType::MsgSnapshot => {
+                self.become_follower(msg.term, msg.from);
+                self.handle_snapshot(msg);
+            }
             MessageType::MsgRequestVoteResponse | MessageType::MsgRequestPreVoteResponse => {
+                // Only handle vote responses corresponding to our candidacy (while in
+                // state Candidate, we may get stale MsgPreVoteResp messages in this term from
+                // our pre-candidate state).
+                if (self.state == StateRole::PreCandidate
+                    && msg.msg_type() != MessageType::MsgRequestPreVoteResponse)
+                    || (self.state == StateRole::Candidate
+                        && msg.msg_type() != MessageType::MsgRequestVoteResponse)
+                {
+                    return Ok(());
+                }
                 self.poll(msg.from, msg.msg_type(), !msg.reject);
             }
             _ => (),
@@ -401,11 +864,77 @@ impl<T: Storage> Raft<T> {
         Ok(())
     }
 
-    fn step_leader(&mut self, msg: Message) -> Result<()> {
+    fn step_leader(&mut self, mut msg: Message) -> Result<()> {
         match msg.msg_type() {
             MessageType::MsgBeat => {
                 self.bcast_heartbeat();
             }
+            MessageType::MsgPropose => {
+                if msg.entries.is_empty() {
+                    panic!("stepped empty MsgProp");
+                }
+                if let Some(transferee) = self.lead_transferee {
+                    debug!(
+                        self.logger,
+                        "[term {term}] transfer leadership to {lead_transferee} is in progress; dropping \
+                         proposal",
+                        term = self.term,
+                        lead_transferee = transferee;
+                    );
+                    return Err(Error::ProposalDropped.into());
+                }
+                if self.prs.get(self.id).is_none() {
+                    // If we are not currently a member of the range (i.e. this node
+                    // was removed from the configuration while serving as leader),
+                    // drop any new proposals.
+                    return Err(Error::ProposalDropped.into());
+                }
+
+                let last_index = self.raft_log.last_index();
+                for (i, e) in msg.entries.iter_mut().enumerate() {
+                    let cc: Box<dyn ConfChangeI> = match e.entry_type() {
+                        EntryType::EntryConfChange => {
+                            Box::new(ConfChange::decode(e.data.as_slice())?)
+                        }
+                        EntryType::EntryConfChangeV2 => {
+                            Box::new(ConfChangeV2::decode(e.data.as_slice())?)
+                        }
+                        EntryType::EntryNormal => continue,
+                    };
+                    let cc = cc.as_v2();
+                    let already_pending = self.pending_conf_index > self.raft_log.applied;
+                    let already_joint = joint(self.prs.conf());
+                    let wants_leave_joint = cc.changes.is_empty();
+
+                    let refused = if already_pending {
+                        format!(
+                            "possible unapplied conf change at index {} (applied to {})",
+                            self.pending_conf_index, self.raft_log.applied
+                        )
+                    } else if already_joint && !wants_leave_joint {
+                        "must transition out of joint config first".to_owned()
+                    } else if !already_joint && wants_leave_joint {
+                        "not in joint state; refusing empty conf change".to_owned()
+                    } else {
+                        String::new()
+                    };
+
+                    if refused.is_empty() {
+                        self.r.pending_conf_index = last_index + i as u64 + 1;
+                    } else {
+                        info!(
+                            self.logger,
+                            "ignoring conf change";
+                            "conf change" => ?cc,
+                            "reason" => refused,
+                            "config" => ?self.prs.conf(),
+                        );
+                        *e = Entry::default();
+                    }
+                }
+                self.append_entry(&mut msg.entries);
+                self.bcast_append();
+            }
             MessageType::MsgCheckQuorum => {
                 if !self.prs.quorum_recently_active() {
                     let term = self.term;
@@ -419,8 +948,68 @@ impl<T: Storage> Raft<T> {
                 self.prs.reset_recent_active();
             }
             MessageType::MsgHeartbeatResponse => {
+                let last_index = self.raft_log.last_index();
+                let pr = match self.prs.get_mut(msg.from) {
+                    Some(pr) => pr,
+                    None => {
+                        debug!(self.r.logger, "no progress available for {}", msg.from);
+                        return Ok(());
+                    }
+                };
+                pr.recent_active = true;
+                // A probe may have been lost; the heartbeat round trip lets it retry.
+                pr.resume();
+                // free one slot for the full inflights window to allow progress.
+                if pr.state == ProgressState::Replicate && pr.ins.full() {
+                    pr.ins.free_first_one();
+                }
+                if pr.matched < last_index {
+                    self.send_append(msg.from);
+                }
+                if msg.context.is_empty() {
+                    return Ok(());
+                }
+                match self.r.read_only.recv_ack(msg.from, &msg.context) {
+                    Some(acks) if self.prs.has_quorum(acks) => (),
+                    _ => return Ok(()),
+                }
+                for rs in self.r.read_only.advance(&msg.context) {
+                    if let Some(m) = self.handle_ready_read_index(rs.req, rs.index) {
+                        self.r.send(m, &mut self.msg);
+                    }
+                }
+            }
+            MessageType::MsgReadIndex => {
+                if !self.commit_to_current_term() {
+                    // Reject read only request when this leader has not committed any log
+                    // entry in its term; the commit index may still be behind.
+                    self.r.pending_read_index_messages.push(msg);
+                    return Ok(());
+                }
+                self.handle_read_index(msg);
+            }
+            MessageType::MsgAppendResponse => {
+                self.handle_append_response(&msg);
+            }
+            MessageType::MsgTransferLeader => {
+                self.handle_transfer_leader(&msg);
+            }
+            MessageType::MsgSnapStatus => {
+                self.handle_snapshot_status(&msg);
+            }
+            MessageType::MsgUnreachable => {
                 if let Some(pr) = self.prs.get_mut(msg.from) {
-                    pr.recent_active = true;
+                    // During optimistic replication, if the remote becomes unreachable,
+                    // there is huge probability that a MsgAppend is lost.
+                    if pr.state == ProgressState::Replicate {
+                        pr.become_probe();
+                    }
+                    debug!(
+                        self.r.logger,
+                        "failed to send message to {from} because it is unreachable",
+                        from = msg.from;
+                        "state" => %pr.state,
+                    );
                 }
             }
             _ => (),
@@ -428,82 +1017,905 @@ impl<T: Storage> Raft<T> {
         Ok(())
     }
 
-    fn step_follower(&mut self, msg: Message) -> Result<()> {
+    fn step_follower(&mut self, mut msg: Message) -> Result<()> {
         match msg.msg_type() {
+            MessageType::MsgPropose => {
+                if self.leader_id == INVALID_ID {
+                    info!(
+                        self.logger,
+                        "no leader at term {term}; dropping proposal",
+                        term = self.term;
+                    );
+                    return Err(Error::ProposalDropped.into());
+                }
+                msg.to = self.leader_id;
+                self.r.send(msg, &mut self.msg);
+            }
             MessageType::MsgHeartbeat => {
                 self.election_elapsed = 0;
                 self.leader_id = msg.from;
-                let mut m = new_message(msg.from, MessageType::MsgHeartbeatResponse, None);
-                m.term = self.term;
-                self.r.send(m, &mut self.msg);
+                self.handle_heartbeat(msg);
+            }
+            MessageType::MsgReadIndex => {
+                if self.leader_id == INVALID_ID {
+                    info!(
+                        self.logger,
+                        "no leader at term {term}; dropping index reading msg",
+                        term = self.term;
+                    );
+                    return Ok(());
+                }
+                msg.to = self.leader_id;
+                msg.from = self.id;
+                self.r.send(msg, &mut self.msg);
+            }
+            MessageType::MsgReadIndexResp => {
+                if msg.entries.len() != 1 {
+                    error!(
+                        self.logger,
+                        "invalid format of MsgReadIndexResp from {}",
+                        msg.from;
+                        "entries count" => msg.entries.len(),
+                    );
+                    return Ok(());
+                }
+                let rs = ReadState {
+                    index: msg.index,
+                    request_ctx: msg.entries.swap_remove(0).data,
+                };
+                self.read_states.push(rs);
+            }
+            MessageType::MsgAppend => {
+                self.election_elapsed = 0;
+                self.leader_id = msg.from;
+                self.handle_append_entries(&msg);
+            }
+            MessageType::MsgSnapshot => {
+                self.election_elapsed = 0;
+                self.leader_id = msg.from;
+                self.handle_snapshot(msg);
+            }
+            MessageType::MsgTransferLeader => {
+                if self.leader_id == INVALID_ID {
+                    info!(
+                        self.logger,
+                        "no leader at term {term}; dropping leader transfer msg",
+                        term = self.term;
+                    );
+                    return Ok(());
+                }
+                msg.to = self.leader_id;
+                self.r.send(msg, &mut self.msg);
+            }
+            MessageType::MsgTimeoutNow => {
+                if !self.promotable() {
+                    info!(
+                        self.logger,
+                        "received MsgTimeoutNow from {from} but is not promotable",
+                        from = msg.from;
+                    );
+                    return Ok(());
+                }
+                info!(
+                    self.logger,
+                    "[term {term}] received MsgTimeoutNow from {from} and starts an election to \
+                     get leadership.",
+                    term = self.term,
+                    from = msg.from;
+                );
+                // Leadership transfers never use pre-vote even if self.pre_vote is true; we
+                // know we are not recovering from a partition so there is no need for the
+                // extra round trip.
+                self.hup(true);
             }
             _ => (),
         }
         Ok(())
     }
 
-    fn bcast_heartbeat(&mut self) {
-        let self_id = self.id;
-        let ids: Vec<u64> = self.prs.voter_ids().into_iter().collect();
-        for id in ids {
-            if id == self_id {
-                continue;
+    fn handle_transfer_leader(&mut self, m: &Message) {
+        let from = m.from;
+        if self.prs.conf().learners.contains(&from) {
+            debug!(
+                self.logger,
+                "ignored transferring leadership";
+                "to" => from,
+            );
+            return;
+        }
+        let lead_transferee = from;
+        if let Some(last_lead_transferee) = self.lead_transferee {
+            if last_lead_transferee == lead_transferee {
+                info!(
+                    self.logger,
+                    "[term {term}] transfer leadership to {lead_transferee} is in progress, ignores request \
+                     to same node {lead_transferee}",
+                    term = self.term,
+                    lead_transferee = lead_transferee;
+                );
+                return;
             }
-            let mut m = new_message(id, MessageType::MsgHeartbeat, Some(self_id));
-            m.term = self.term;
-            self.r.send(m, &mut self.msg);
+            self.abort_leader_transfer();
+            info!(
+                self.logger,
+                "[term {term}] abort previous transferring leadership to {last_lead_transferee}",
+                term = self.term,
+                last_lead_transferee = last_lead_transferee;
+            );
         }
-    }
-
-    fn poll(&mut self, from: u64, _m_t: MessageType, vote: bool) -> VoteResult {
-        self.prs.record_vote(from, vote);
-        let (gr, rj, res) = self.prs.tally_votes();
+        if lead_transferee == self.id {
+            debug!(
+                self.logger,
+                "already leader; ignored transferring leadership to self";
+            );
+            return;
+        }
+        let matched = match self.prs.get(from) {
+            Some(pr) => pr.matched,
+            None => {
+                debug!(self.logger, "no progress available for {}", from);
+                return;
+            }
+        };
+        // Transfer leadership to third party.
         info!(
             self.logger,
-            "poll result";
-            "from" => from,
-            "vote" => vote,
-            "approvals" => gr,
-            "rejections" => rj,
-            "result" => ?res,
+            "[term {term}] starts to transfer leadership to {lead_transferee}",
+            term = self.term,
+            lead_transferee = lead_transferee;
+            "match" => matched,
         );
-
-        match res {
-            VoteResult::Won => {
-                self.become_leader();
-            }
-            VoteResult::Lost => {
-                let term = self.term;
-                self.become_follower(term, INVALID_ID);
-            }
-            VoteResult::Pending => (),
+        // Transfer leadership should be finished in one electionTimeout
+        // so reset r.electionElapsed.
+        self.election_elapsed = 0;
+        self.lead_transferee = Some(lead_transferee);
+        if matched == self.raft_log.last_index() {
+            self.send_timeout_now(lead_transferee);
+            info!(
+                self.logger,
+                "sends MsgTimeoutNow to {lead_transferee} immediately as {lead_transferee} already has up-to-date log",
+                lead_transferee = lead_transferee;
+            );
+        } else {
+            self.send_append(lead_transferee);
         }
-        res
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::storage::MemStorage;
-    use raftpb::proto::ConfState;
-    use slog::o;
+    /// Issues a message to timeout immediately.
+    fn send_timeout_now(&mut self, to: u64) {
+        let mut m = new_message(to, MessageType::MsgTimeoutNow, Some(self.id));
+        m.term = self.term;
+        self.r.send(m, &mut self.msg);
+    }
 
-    fn new_test_logger() -> Logger {
-        slog::Logger::root(slog::Discard, o!())
+    /// Stops the transfer of a leader.
+    pub fn abort_leader_transfer(&mut self) {
+        self.lead_transferee = None;
     }
 
-    fn new_test_config(id: u64, voters: Vec<u64>) -> (Config, MemStorage) {
-        let mut conf_state = ConfState::default();
-        conf_state.voters = voters;
-        let storage = MemStorage::new_with_conf_state(conf_state);
-        let conf = Config {
-            id,
-            heartbeat_tick: 1,
-            election_tick: 10,
-            min_election_tick: 10,
-            max_election_tick: 20,
-            check_quorum: true,
+    /// Sends an append RPC with new entries (if any) and the current commit index
+    /// to the given peer, unless its progress is paused.
+    fn send_append(&mut self, to: u64) {
+        self.maybe_send_append(to, true);
+    }
+
+    /// Sends an append RPC with new entries to the given peer, if necessary.
+    /// Returns true if a message was sent. The `send_if_empty` argument controls
+    /// whether messages with no entries will be sent ("empty" messages are useful
+    /// to convey updated commit indexes, but are undesirable when we're sending
+    /// multiple messages in a batch).
+    fn maybe_send_append(&mut self, to: u64, send_if_empty: bool) -> bool {
+        let (next_idx, pending_request_snapshot) = match self.prs.get(to) {
+            Some(pr) if pr.awaiting_fetch => {
+                debug!(
+                    self.logger,
+                    "skip sending append to {to} while its entries are fetched",
+                    to = to;
+                );
+                return false;
+            }
+            Some(pr) if pr.is_paused() => {
+                debug!(
+                    self.logger,
+                    "skip sending append to paused peer {to}",
+                    to = to;
+                    "state" => %pr.state,
+                );
+                return false;
+            }
+            Some(pr) => (pr.next_idx, pr.pending_request_snapshot),
+            None => return false,
+        };
+        if pending_request_snapshot != INVALID_INDEX {
+            // The follower asked for a snapshot, the log cannot help it.
+            return self.send_snapshot(to);
+        }
+        let prev_index = next_idx - 1;
+        let log_term = self.raft_log.term(prev_index);
+        let context = GetEntriesContext(GetEntriesFor::SendAppend {
+            to,
+            term: self.term,
+            aggressively: !send_if_empty,
+        });
+        let ents = self.raft_log.entries(
+            next_idx,
+            self.raft_log.last_index() + 1,
+            Some(self.max_msg_size),
+            context,
+        );
+        let (log_term, ents) = match (log_term, ents) {
+            (Ok(log_term), Ok(ents)) => (log_term, ents),
+            (Err(Error::Store(StorageError::Compacted)), _)
+            | (_, Err(Error::Store(StorageError::Compacted))) => {
+                // The entries the peer needs are gone, send a snapshot instead.
+                return self.send_snapshot(to);
+            }
+            (_, Err(Error::Store(StorageError::LogTemporarilyUnavailable))) => {
+                // Storage is fetching the entries, hold the peer until
+                // `on_entries_fetched` is called.
+                debug!(
+                    self.logger,
+                    "entries for {to} are temporarily unavailable",
+                    to = to;
+                    "next_idx" => next_idx,
+                );
+                if let Some(pr) = self.prs.get_mut(to) {
+                    pr.awaiting_fetch = true;
+                }
+                return false;
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                debug!(
+                    self.logger,
+                    "failed to get entries for {to}",
+                    to = to;
+                    "next_idx" => next_idx,
+                    "err" => %e,
+                );
+                return false;
+            }
+        };
+        if ents.is_empty() && !send_if_empty {
+            return false;
+        }
+
+        if let Some(last) = ents.last() {
+            // Probes wait for an answer; replication pipelines the next batch.
+            if let Some(pr) = self.prs.get_mut(to) {
+                pr.update_state(last.index);
+            }
+        }
+
+        let mut m = new_message(to, MessageType::MsgAppend, Some(self.id));
+        m.term = self.term;
+        m.index = prev_index;
+        m.log_term = log_term;
+        m.entries = ents;
+        m.commit = self.raft_log.committed;
+        self.r.send(m, &mut self.msg);
+        true
+    }
+
+    /// Resumes the append that was held back because storage returned
+    /// `LogTemporarilyUnavailable` for `context`. It's a no-op if the node is no
+    /// longer the leader of the term the entries were requested in, or the peer
+    /// has been removed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the context doesn't allow fetching entries asynchronously.
+    pub fn on_entries_fetched(&mut self, context: GetEntriesContext) {
+        match context.0 {
+            GetEntriesFor::SendAppend {
+                to,
+                term,
+                aggressively,
+            } => {
+                if self.term != term || self.state != StateRole::Leader {
+                    return;
+                }
+                match self.prs.get_mut(to) {
+                    Some(pr) => pr.awaiting_fetch = false,
+                    None => return,
+                }
+                self.maybe_send_append(to, !aggressively);
+            }
+            GetEntriesFor::Empty(true) => {}
+            _ => panic!("shouldn't call callback on non-async context {:?}", context),
+        }
+    }
+
+    /// Sends the current snapshot to the given peer and moves its progress into
+    /// Snapshot state. Returns false if there was nothing to send, in which case
+    /// it is retried on a later append.
+    fn send_snapshot(&mut self, to: u64) -> bool {
+        let pending_request_snapshot = match self.prs.get(to) {
+            Some(pr) if !pr.recent_active => {
+                debug!(
+                    self.logger,
+                    "ignore sending snapshot to {to} since it is not recently active",
+                    to = to;
+                );
+                return false;
+            }
+            Some(pr) => pr.pending_request_snapshot,
+            None => return false,
+        };
+
+        let snapshot = match self.raft_log.snapshot(pending_request_snapshot, to) {
+            Ok(snapshot) => snapshot,
+            Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable)) => {
+                debug!(
+                    self.logger,
+                    "failed to send snapshot to {to} because snapshot is temporarily \
+                     unavailable",
+                    to = to;
+                );
+                return false;
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+        };
+        if snapshot.is_empty() {
+            panic!("need non-empty snapshot");
+        }
+        let (sindex, sterm) = snapshot
+            .metadata
+            .as_ref()
+            .map_or((0, 0), |m| (m.index, m.term));
+        debug!(
+            self.logger,
+            "[firstindex: {first_index}, commit: {committed}] sent snapshot[index: {snapshot_index}, term: {snapshot_term}] to {to}",
+            first_index = self.raft_log.first_index(),
+            committed = self.raft_log.committed,
+            snapshot_index = sindex,
+            snapshot_term = sterm,
+            to = to;
+        );
+        if let Some(pr) = self.prs.get_mut(to) {
+            pr.become_snapshot(sindex);
+        }
+
+        let mut m = new_message(to, MessageType::MsgSnapshot, Some(self.id));
+        m.term = self.term;
+        m.snapshot = Some(snapshot);
+        self.r.send(m, &mut self.msg);
+        true
+    }
+
+    /// Sends an append to every peer, voters and learners alike.
+    fn bcast_append(&mut self) {
+        let self_id = self.id;
+        let ids = self.prs.ids();
+        for id in ids {
+            if id == self_id {
+                continue;
+            }
+            self.send_append(id);
+        }
+    }
+
+    /// For a given message, advance the commit index and answer the heartbeat.
+    fn handle_heartbeat(&mut self, m: Message) {
+        self.raft_log.commit_to(m.commit);
+        let mut to_send = new_message(m.from, MessageType::MsgHeartbeatResponse, Some(self.id));
+        to_send.term = self.term;
+        to_send.context = m.context;
+        self.r.send(to_send, &mut self.msg);
+    }
+
+    /// For a given message, append the entries to the log.
+    fn handle_append_entries(&mut self, m: &Message) {
+        let mut to_send = new_message(m.from, MessageType::MsgAppendResponse, Some(self.id));
+        to_send.term = self.term;
+
+        if self.pending_request_snapshot != INVALID_INDEX {
+            // Keep asking until the leader sends the snapshot.
+            self.send_request_snapshot();
+            return;
+        }
+
+        if m.index < self.raft_log.committed {
+            // Everything up to our commit index is already in the log.
+            to_send.index = self.raft_log.committed;
+            self.r.send(to_send, &mut self.msg);
+            return;
+        }
+
+        match self
+            .raft_log
+            .maybe_append(m.index, m.log_term, m.commit, &m.entries)
+        {
+            Some((_, last_idx)) => {
+                to_send.index = last_idx;
+            }
+            None => {
+                debug!(
+                    self.logger,
+                    "rejected msgApp [logterm: {msg_log_term}, index: {msg_index}] from {from}",
+                    msg_log_term = m.log_term,
+                    msg_index = m.index,
+                    from = m.from;
+                    "index" => m.index,
+                    "logterm" => ?self.raft_log.term(m.index).ok(),
+                );
+                // Return a hint to the leader about the maximum index and term that the
+                // two logs could be divergent at. Do this by searching through the
+                // follower's log for the maximum (index, term) pair with a term <= the
+                // MsgAppend's LogTerm and an index <= the MsgAppend's Index. This can
+                // help skip all indexes in the follower's uncommitted tail with terms
+                // greater than the MsgAppend's LogTerm.
+                let hint_index = cmp::min(m.index, self.raft_log.last_index());
+                let (hint_index, hint_term) =
+                    self.raft_log.find_conflict_by_term(hint_index, m.log_term);
+                let hint_term = match hint_term {
+                    Some(t) => t,
+                    None => panic!("term({}) must be valid", hint_index),
+                };
+                to_send.index = m.index;
+                to_send.reject = true;
+                to_send.reject_hint = hint_index;
+                to_send.log_term = hint_term;
+            }
+        }
+        self.r.send(to_send, &mut self.msg);
+    }
+
+    fn handle_snapshot(&mut self, mut m: Message) {
+        let snapshot = m.snapshot.take().unwrap_or_default();
+        let (sindex, sterm) = snapshot
+            .metadata
+            .as_ref()
+            .map_or((0, 0), |m| (m.index, m.term));
+        let mut to_send = new_message(m.from, MessageType::MsgAppendResponse, Some(self.id));
+        to_send.term = self.term;
+        if self.restore(snapshot) {
+            info!(
+                self.logger,
+                "[commit: {commit}, term: {term}] restored snapshot [index: {snapshot_index}, term: {snapshot_term}]",
+                term = self.term,
+                commit = self.raft_log.committed,
+                snapshot_index = sindex,
+                snapshot_term = sterm;
+            );
+            to_send.index = self.raft_log.last_index();
+        } else {
+            info!(
+                self.logger,
+                "[commit: {commit}] ignored snapshot [index: {snapshot_index}, term: {snapshot_term}]",
+                commit = self.raft_log.committed,
+                snapshot_index = sindex,
+                snapshot_term = sterm;
+            );
+            to_send.index = self.raft_log.committed;
+        }
+        self.r.send(to_send, &mut self.msg);
+    }
+
+    /// Recovers the state machine from a snapshot. It restores the log and the
+    /// configuration of state machine. Returns false if the snapshot was ignored.
+    pub fn restore(&mut self, snap: Snapshot) -> bool {
+        let meta = snap.metadata.clone().unwrap_or_default();
+        if meta.index < self.raft_log.committed {
+            return false;
+        }
+        if self.state != StateRole::Follower {
+            // This is defense-in-depth: if the leader somehow ended up applying a
+            // snapshot, it could move into a new term without moving into a
+            // follower state. This should never fire, but if it did, we'd have
+            // prevented damage by returning early, so log only a loud warning.
+            //
+            // At the time of writing, the instance is guaranteed to be in follower
+            // state when this method is called.
+            warn!(self.logger, "non-follower attempted to restore snapshot"; "state" => ?self.state);
+            let term = self.term + 1;
+            self.become_follower(term, INVALID_ID);
+            return false;
+        }
+
+        // More defense-in-depth: throw away snapshot if recipient is not in the
+        // config. This shouldn't ever happen (at the time of writing) but lots of
+        // code here and there assumes that r.id is in the progress tracker.
+        let cs = meta.conf_state.clone().unwrap_or_default();
+        if !cs
+            .voters
+            .iter()
+            .chain(&cs.learners)
+            .chain(&cs.voters_outgoing)
+            .any(|id| *id == self.id)
+        {
+            warn!(
+                self.logger,
+                "attempted to restore snapshot but it is not in the ConfState";
+                "conf_state" => ?cs,
+            );
+            return false;
+        }
+
+        // Now go ahead and actually restore.
+        if self.pending_request_snapshot == INVALID_INDEX
+            && self.raft_log.match_term(meta.index, meta.term)
+        {
+            info!(
+                self.logger,
+                "fast-forwarded commit to snapshot";
+                "commit" => self.raft_log.committed,
+                "last_index" => self.raft_log.last_index(),
+                "last_term" => self.raft_log.last_term(),
+                "snapshot_index" => meta.index,
+                "snapshot_term" => meta.term,
+            );
+            self.r.raft_log.commit_to(meta.index);
+            return false;
+        }
+
+        self.r.raft_log.restore(snap);
+
+        // Reset the configuration and add the (potentially updated) peers in anew.
+        self.prs.clear();
+        let last_index = self.raft_log.last_index();
+        if let Err(e) = confchange::restore::restore(&mut self.prs, last_index, &cs) {
+            // This should never happen. Either there's a bug in our config change
+            // handling or the client corrupted the conf change.
+            panic!("unable to restore config {:?}: {}", cs, e);
+        }
+        let self_id = self.id;
+        if let Some(pr) = self.prs.get_mut(self_id) {
+            pr.maybe_update(last_index);
+        }
+        self.pending_request_snapshot = INVALID_INDEX;
+        true
+    }
+
+    fn handle_snapshot_status(&mut self, m: &Message) {
+        let pr = match self.prs.get_mut(m.from) {
+            Some(pr) => pr,
+            None => {
+                debug!(self.r.logger, "no progress available for {}", m.from);
+                return;
+            }
+        };
+        if pr.state != ProgressState::Snapshot {
+            return;
+        }
+        if m.reject {
+            pr.snapshot_failure();
+            pr.become_probe();
+            debug!(
+                self.r.logger,
+                "snapshot failed, resumed sending replication messages to {from}",
+                from = m.from;
+                "next_idx" => pr.next_idx,
+            );
+        } else {
+            pr.become_probe();
+            debug!(
+                self.r.logger,
+                "snapshot succeeded, resumed sending replication messages to {from}",
+                from = m.from;
+                "next_idx" => pr.next_idx,
+            );
+        }
+        // If snapshot finish, wait for the MsgAppendResponse from the remote node before sending
+        // out the next MsgAppend.
+        // If snapshot failure, wait for a heartbeat interval before next try
+        pr.pause();
+        pr.pending_request_snapshot = INVALID_INDEX;
+    }
+
+    /// Asks the leader for a snapshot covering at least the current last index,
+    /// e.g. because the application lost its state machine.
+    pub fn request_snapshot(&mut self) -> Result<()> {
+        if self.state == StateRole::Leader {
+            info!(
+                self.logger,
+                "can not request snapshot on leader; dropping request snapshot";
+            );
+        } else if self.leader_id == INVALID_ID {
+            info!(
+                self.logger,
+                "drop request snapshot because of no leader";
+                "term" => self.term,
+            );
+        } else if self.raft_log.unstable_snapshot().is_some() {
+            info!(
+                self.logger,
+                "there is a pending snapshot; dropping request snapshot";
+            );
+        } else if self.pending_request_snapshot != INVALID_INDEX {
+            info!(
+                self.logger,
+                "there is a pending snapshot request; dropping request snapshot";
+            );
+        } else {
+            let request_index = self.raft_log.last_index();
+            let request_index_term = self.raft_log.term(request_index)?;
+            if self.term == request_index_term {
+                info!(
+                    self.logger,
+                    "will send request snapshot message to {to}",
+                    to = self.leader_id;
+                    "index" => request_index,
+                    "term" => self.term,
+                );
+                self.pending_request_snapshot = request_index;
+                self.send_request_snapshot();
+                return Ok(());
+            }
+            info!(
+                self.logger,
+                "mismatched term; dropping request snapshot";
+                "term" => self.term,
+                "last_term" => request_index_term,
+            );
+        }
+        Err(Error::RequestSnapshotDropped.into())
+    }
+
+    /// Rejects the leader's appends with the requested snapshot index attached, so
+    /// that the leader moves to sending a snapshot.
+    fn send_request_snapshot(&mut self) {
+        let mut m = new_message(self.leader_id, MessageType::MsgAppendResponse, Some(self.id));
+        m.term = self.term;
+        m.index = self.raft_log.committed;
+        m.reject = true;
+        m.reject_hint = self.raft_log.last_index();
+        m.request_snapshot = self.pending_request_snapshot;
+        self.r.send(m, &mut self.msg);
+    }
+
+    fn handle_append_response(&mut self, m: &Message) {
+        let last_index = self.raft_log.last_index();
+        // The follower reported the last index that could match; skip every entry of
+        // ours above it whose term is newer than what the follower has there.
+        let mut next_probe_index = m.reject_hint;
+        if m.reject && m.log_term > 0 {
+            next_probe_index = self
+                .raft_log
+                .find_conflict_by_term(m.reject_hint, m.log_term)
+                .0;
+        }
+        let pr = match self.prs.get_mut(m.from) {
+            Some(pr) => pr,
+            None => {
+                debug!(self.r.logger, "no progress available for {}", m.from);
+                return;
+            }
+        };
+        pr.recent_active = true;
+
+        if m.reject {
+            debug!(
+                self.r.logger,
+                "received msgAppend rejection";
+                "reject_hint" => m.reject_hint,
+                "from" => m.from,
+                "index" => m.index,
+            );
+            if pr.maybe_decr_to(m.index, next_probe_index, m.request_snapshot) {
+                debug!(
+                    self.r.logger,
+                    "decreased progress of {}",
+                    m.from;
+                    "next_idx" => pr.next_idx,
+                );
+                if pr.state == ProgressState::Replicate {
+                    pr.become_probe();
+                }
+                self.send_append(m.from);
+            }
+            return;
+        }
+
+        let old_paused = pr.is_paused();
+        if !pr.maybe_update(m.index) {
+            return;
+        }
+        match pr.state {
+            ProgressState::Probe => pr.become_replicate(),
+            ProgressState::Snapshot => {
+                if pr.matched >= pr.pending_snapshot {
+                    debug!(
+                        self.r.logger,
+                        "snapshot succeeded, resumed sending replication messages to {from}",
+                        from = m.from;
+                    );
+                    pr.become_probe();
+                }
+            }
+            ProgressState::Replicate => pr.ins.free_to(m.index),
+        }
+        let matched = pr.matched;
+        if self.maybe_commit() {
+            self.release_pending_read_index_messages();
+            self.bcast_append();
+        } else if old_paused {
+            // The peer was waiting on this answer; tell it about the commit index
+            // even if there is nothing new to send.
+            self.send_append(m.from);
+        }
+        // Send as many batches as the inflight window allows.
+        while self.maybe_send_append(m.from, false) {}
+
+        // Transfer leadership is in progress.
+        if Some(m.from) == self.lead_transferee && matched == last_index {
+            info!(
+                self.logger,
+                "sent MsgTimeoutNow to {from} after received MsgAppResp",
+                from = m.from;
+            );
+            self.send_timeout_now(m.from);
+        }
+    }
+
+    fn bcast_heartbeat(&mut self) {
+        let ctx = self.read_only.last_pending_request_ctx();
+        self.bcast_heartbeat_with_ctx(ctx);
+    }
+
+    fn bcast_heartbeat_with_ctx(&mut self, ctx: Option<Vec<u8>>) {
+        let self_id = self.id;
+        let ids = self.prs.ids();
+        for id in ids {
+            if id == self_id {
+                continue;
+            }
+            // The follower might not have all the committed entries yet, so only
+            // forward the commit index as far as it has been matched.
+            let matched = self.prs.get(id).map_or(0, |pr| pr.matched);
+            let mut m = new_message(id, MessageType::MsgHeartbeat, Some(self_id));
+            m.term = self.term;
+            m.commit = cmp::min(matched, self.raft_log.committed);
+            if let Some(ctx) = &ctx {
+                m.context = ctx.clone();
+            }
+            self.r.send(m, &mut self.msg);
+        }
+    }
+
+    fn handle_read_index(&mut self, msg: Message) {
+        let read_index = self.raft_log.committed;
+        if self.prs.is_singleton() {
+            // A single voter is its own quorum, so the read can be served at once.
+            if let Some(m) = self.handle_ready_read_index(msg, read_index) {
+                self.r.send(m, &mut self.msg);
+            }
+            return;
+        }
+        if self.read_only.option == ReadOnlyOption::LeaseBased {
+            // `check_quorum` makes the leader step down once it loses contact with a
+            // quorum, which is what the lease relies on to serve the commit index
+            // without a heartbeat round.
+            if let Some(m) = self.handle_ready_read_index(msg, read_index) {
+                self.r.send(m, &mut self.msg);
+            }
+            return;
+        }
+        let ctx = msg.entries[0].data.clone();
+        let self_id = self.id;
+        self.r.read_only.add_request(read_index, msg, self_id);
+        // Confirm leadership with a heartbeat round carrying the request context.
+        self.bcast_heartbeat_with_ctx(Some(ctx));
+    }
+
+    /// Serves the read requests stashed while the leader had not yet committed an
+    /// entry in its own term.
+    fn release_pending_read_index_messages(&mut self) {
+        if !self.commit_to_current_term() {
+            return;
+        }
+        let msgs = mem::take(&mut self.r.pending_read_index_messages);
+        for m in msgs {
+            self.handle_read_index(m);
+        }
+    }
+
+    /// Answers a read request once leadership has been confirmed at `index`. Local
+    /// requests become a `ReadState`; forwarded ones are answered with
+    /// `MsgReadIndexResp`.
+    fn handle_ready_read_index(&mut self, mut req: Message, index: u64) -> Option<Message> {
+        if req.from == INVALID_ID || req.from == self.id {
+            let rs = ReadState {
+                index,
+                request_ctx: req.entries.swap_remove(0).data,
+            };
+            self.read_states.push(rs);
+            return None;
+        }
+        let mut m = new_message(req.from, MessageType::MsgReadIndexResp, Some(self.id));
+        m.term = self.term;
+        m.index = index;
+        m.entries = req.entries;
+        Some(m)
+    }
+
+    fn poll(&mut self, from: u64, _m_t: MessageType, vote: bool) -> VoteResult {
+        self.prs.record_vote(from, vote);
+        let (gr, rj, res) = self.prs.tally_votes();
+        info!(
+            self.logger,
+            "poll result";
+            "from" => from,
+            "vote" => vote,
+            "approvals" => gr,
+            "rejections" => rj,
+            "result" => ?res,
+        );
+
+        match res {
+            VoteResult::Won => {
+                if self.state == StateRole::PreCandidate {
+                    self.campaign(CAMPAIGN_ELECTION);
+                } else {
+                    self.become_leader();
+                    self.bcast_append();
+                }
+            }
+            VoteResult::Lost => {
+                let term = self.term;
+                self.become_follower(term, INVALID_ID);
+            }
+            VoteResult::Pending => (),
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+    use raftpb::proto::{ConfChangeTransition, ConfChangeType, SnapshotMetadata};
+    use slog::o;
+
+    fn new_test_logger() -> Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            ..Default::default()
+        }
+    }
+
+    /// Writes the unstable entries to storage and reports them as persisted, like the
+    /// application does when handling a `Ready`.
+    fn persist(r: &mut Raft<MemStorage>) {
+        let ents = r.raft_log.unstable_entries().to_vec();
+        if let Some(last) = ents.last() {
+            r.raft_log.storage.wl().append(&ents).unwrap();
+            r.raft_log.stable_to(last.index, last.term);
+            r.on_persist_entries(last.index, last.term);
+        }
+    }
+
+    /// Persists a new leader's log and acks it from `peer`, which commits the
+    /// leader's no-op entry in a two or three node group.
+    fn commit_noop(r: &mut Raft<MemStorage>, peer: u64) {
+        persist(r);
+        let mut resp = new_message(r.id, MessageType::MsgAppendResponse, Some(peer));
+        resp.term = r.term;
+        resp.index = r.raft_log.last_index();
+        r.step(resp).unwrap();
+        assert!(r.commit_to_current_term());
+        r.msg.clear();
+    }
+
+    fn new_test_config(id: u64, voters: Vec<u64>) -> (Config, MemStorage) {
+        let conf_state = ConfState {
+            voters,
+            ..Default::default()
+        };
+        let storage = MemStorage::new_with_conf_state(conf_state);
+        let conf = Config {
+            id,
+            heartbeat_tick: 1,
+            election_tick: 10,
+            min_election_tick: 10,
+            max_election_tick: 20,
+            check_quorum: true,
+            ..Default::default()
         };
         (conf, storage)
     }
@@ -522,6 +1934,40 @@ mod tests {
         assert_eq!(r.leader_id, 1);
     }
 
+    #[test]
+    fn test_become_leader_appends_noop() {
+        let (conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+
+        let ents = r.raft_log.unstable_entries().to_vec();
+        assert_eq!(ents.len(), 1);
+        assert_eq!((ents[0].index, ents[0].term), (1, r.term));
+        assert_eq!(ents[0].entry_type(), EntryType::EntryNormal);
+        assert!(ents[0].data.is_empty());
+        assert!(!r.commit_to_current_term());
+
+        // Reads wait until the no-op entry commits.
+        r.step(new_read_index(b"ctx")).unwrap();
+        assert!(r.msg.is_empty());
+        assert!(r.read_states.is_empty());
+
+        persist(&mut r);
+        let mut resp = new_message(1, MessageType::MsgAppendResponse, Some(2));
+        resp.term = r.term;
+        resp.index = 1;
+        r.step(resp).unwrap();
+        assert!(r.commit_to_current_term());
+        let hb = r
+            .msg
+            .iter()
+            .find(|m| m.msg_type() == MessageType::MsgHeartbeat)
+            .unwrap();
+        assert_eq!(hb.context, b"ctx");
+    }
+
     #[test]
     fn test_step_candidate_poll_won() {
         let (conf, storage) = new_test_config(1, vec![1, 2, 3]);
@@ -565,6 +2011,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn test_election_safety() {
         // Node 1 has a stale log, Node 2 has a fresh log.
         // Node 1 tries to campaign.
@@ -627,4 +2074,1143 @@ mod tests {
         // It should step down because only 1/3 nodes are active
         assert_eq!(r.state, StateRole::Follower);
     }
+
+    #[test]
+    fn test_follower_append_entries() {
+        let (conf, storage) = new_test_config(2, vec![1, 2, 3]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+
+        let mut m = new_message(2, MessageType::MsgAppend, Some(1));
+        m.term = 1;
+        m.index = 0;
+        m.log_term = 0;
+        m.entries = vec![new_entry(1, 1), new_entry(2, 1)];
+        m.commit = 1;
+        r.step(m).unwrap();
+
+        assert_eq!(r.leader_id, 1);
+        assert_eq!(r.raft_log.last_index(), 2);
+        assert_eq!(r.raft_log.committed, 1);
+        let resp = r.msg.pop().unwrap();
+        assert_eq!(resp.msg_type(), MessageType::MsgAppendResponse);
+        assert!(!resp.reject);
+        assert_eq!(resp.index, 2);
+
+        // A new leader overwrites the uncommitted entry 2.
+        let mut m = new_message(2, MessageType::MsgAppend, Some(3));
+        m.term = 2;
+        m.index = 1;
+        m.log_term = 1;
+        m.entries = vec![new_entry(2, 2), new_entry(3, 2)];
+        r.step(m).unwrap();
+
+        assert_eq!(r.leader_id, 3);
+        assert_eq!(r.raft_log.last_index(), 3);
+        assert_eq!(r.raft_log.term(2).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_follower_reject_mismatched_append() {
+        let (conf, storage) = new_test_config(2, vec![1, 2, 3]);
+        storage.wl().append(&[new_entry(1, 1)]).unwrap();
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+
+        let mut m = new_message(2, MessageType::MsgAppend, Some(1));
+        m.term = 2;
+        m.index = 3;
+        m.log_term = 2;
+        m.entries = vec![new_entry(4, 2)];
+        r.step(m).unwrap();
+
+        let resp = r.msg.pop().unwrap();
+        assert_eq!(resp.msg_type(), MessageType::MsgAppendResponse);
+        assert!(resp.reject);
+        assert_eq!(resp.index, 3);
+        assert_eq!(resp.reject_hint, 1);
+        assert_eq!(r.raft_log.last_index(), 1);
+    }
+
+    #[test]
+    fn test_leader_replicates_log() {
+        let (conf1, storage1) = new_test_config(1, vec![1, 2]);
+        storage1
+            .wl()
+            .append(&[new_entry(1, 1), new_entry(2, 1)])
+            .unwrap();
+        let (conf2, storage2) = new_test_config(2, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+
+        r1.become_candidate();
+        r1.become_leader();
+        r1.bcast_append();
+
+        // The first probe starts right after the leader's last entry and gets rejected,
+        // the leader then backs off and ships the whole log.
+        for _ in 0..2 {
+            let m = r1.msg.pop().unwrap();
+            assert_eq!(m.msg_type(), MessageType::MsgAppend);
+            r2.step(m).unwrap();
+            let resp = r2.msg.pop().unwrap();
+            r1.step(resp).unwrap();
+        }
+        // The quorum now holds the persisted part of the log, so the leader commits
+        // it and lets the follower know.
+        assert_eq!(r1.raft_log.committed, 2);
+        let m = r1.msg.pop().unwrap();
+        assert!(r1.msg.is_empty());
+        assert_eq!(m.commit, 2);
+        r2.step(m).unwrap();
+        assert_eq!(r2.raft_log.committed, 2);
+        // The leader's no-op entry follows the old log.
+        assert_eq!(r2.raft_log.last_index(), 3);
+        assert_eq!(r2.raft_log.term(2).unwrap(), 1);
+        let pr = r1.prs.get(2).unwrap();
+        assert_eq!(pr.matched, 3);
+        assert_eq!(pr.next_idx, 4);
+    }
+
+    #[test]
+    fn test_leader_propose() {
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+
+        r.propose(b"ctx".to_vec(), b"foo".to_vec()).unwrap();
+        assert_eq!(r.raft_log.last_index(), 2);
+        let ents = r
+            .raft_log
+            .entries(1, 3, None, GetEntriesContext::empty(false))
+            .unwrap();
+        // The empty entry appended when becoming leader.
+        assert_eq!(ents[0].term, r.term);
+        assert!(ents[0].data.is_empty());
+        assert_eq!(ents[1].term, r.term);
+        assert_eq!(ents[1].entry_type(), EntryType::EntryNormal);
+        assert_eq!(ents[1].data, b"foo");
+        assert_eq!(ents[1].context, b"ctx");
+        // The leader only acknowledges its own entries once they are persisted.
+        assert_eq!(r.prs.get(1).unwrap().matched, 0);
+        persist(&mut r);
+        assert_eq!(r.prs.get(1).unwrap().matched, 2);
+
+        let m = r.msg.pop().unwrap();
+        assert_eq!(m.msg_type(), MessageType::MsgAppend);
+        assert_eq!(m.to, 2);
+        assert_eq!(m.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_follower_forward_proposal() {
+        let (conf, storage) = new_test_config(2, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+
+        let err = r.propose(vec![], b"foo".to_vec()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ProposalDropped)
+        ));
+        assert!(r.msg.is_empty());
+
+        r.become_follower(1, 1);
+        r.propose(vec![], b"foo".to_vec()).unwrap();
+        let m = r.msg.pop().unwrap();
+        assert_eq!(m.msg_type(), MessageType::MsgPropose);
+        assert_eq!(m.to, 1);
+        assert_eq!(m.entries[0].data, b"foo");
+        assert_eq!(r.raft_log.last_index(), 0);
+    }
+
+    #[test]
+    fn test_leader_drop_proposal_during_transfer() {
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        r.lead_transferee = Some(2);
+
+        let err = r.propose(vec![], b"foo".to_vec()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ProposalDropped)
+        ));
+        assert_eq!(r.raft_log.last_index(), 1);
+    }
+
+    #[test]
+    fn test_pre_vote_keeps_term() {
+        let (mut conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        conf.pre_vote = true;
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+
+        r.step(new_message(1, MessageType::MsgHup, None)).unwrap();
+        assert_eq!(r.state, StateRole::PreCandidate);
+        assert_eq!(r.term, 0);
+        assert_eq!(r.vote, INVALID_ID);
+        let msgs: Vec<Message> = r.msg.drain(..).collect();
+        assert_eq!(msgs.len(), 2);
+        for m in &msgs {
+            assert_eq!(m.msg_type(), MessageType::MsgRequestPreVote);
+            assert_eq!(m.term, 1);
+        }
+
+        // A voter grants the pre-vote without touching its own term or vote.
+        let (conf2, storage2) = new_test_config(2, vec![1, 2, 3]);
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        r2.step(msgs[0].clone()).unwrap();
+        assert_eq!(r2.term, 0);
+        assert_eq!(r2.vote, INVALID_ID);
+        let resp = r2.msg.pop().unwrap();
+        assert_eq!(resp.msg_type(), MessageType::MsgRequestPreVoteResponse);
+        assert!(!resp.reject);
+        assert_eq!(resp.term, 1);
+
+        // Winning the pre-vote quorum starts the real election.
+        r.step(resp).unwrap();
+        assert_eq!(r.state, StateRole::Candidate);
+        assert_eq!(r.term, 1);
+        assert_eq!(r.vote, 1);
+        let m = r.msg.pop().unwrap();
+        assert_eq!(m.msg_type(), MessageType::MsgRequestVote);
+        assert_eq!(m.term, 1);
+    }
+
+    #[test]
+    fn test_pre_vote_lost_does_not_disrupt() {
+        let (mut conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        conf.pre_vote = true;
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+
+        // The other voters refuse, e.g. because they still hear from a leader.
+        r.step(new_message(1, MessageType::MsgHup, None)).unwrap();
+        for from in [2, 3] {
+            let mut m = new_message(1, MessageType::MsgRequestPreVoteResponse, Some(from));
+            m.term = 0;
+            m.reject = true;
+            r.step(m).unwrap();
+        }
+        assert_eq!(r.state, StateRole::Follower);
+        assert_eq!(r.term, 0);
+    }
+
+    #[test]
+    fn test_leader_transfer_to_up_to_date_node() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2, 3]);
+        let (conf2, storage2) = new_test_config(2, vec![1, 2, 3]);
+        let (conf3, storage3) = new_test_config(3, vec![1, 2, 3]);
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        let mut r3 = Raft::new(&conf3, storage3, &logger).unwrap();
+        r1.become_candidate();
+        r1.become_leader();
+        r2.become_follower(1, 1);
+        r3.become_follower(1, 1);
+        // Bring both followers up to date with the leader's no-op entry.
+        r1.bcast_append();
+        for m in r1.msg.drain(..).collect::<Vec<_>>() {
+            let r = if m.to == 2 { &mut r2 } else { &mut r3 };
+            r.step(m).unwrap();
+            r1.step(r.msg.pop().unwrap()).unwrap();
+        }
+        r1.msg.clear();
+
+        r1.step(new_message(INVALID_ID, MessageType::MsgTransferLeader, Some(2)))
+            .unwrap();
+        assert_eq!(r1.lead_transferee, Some(2));
+        let timeout_now = r1.msg.pop().unwrap();
+        assert_eq!(timeout_now.msg_type(), MessageType::MsgTimeoutNow);
+        assert_eq!(timeout_now.to, 2);
+
+        // The transferee campaigns right away, even though the voters are still
+        // within the old leader's lease.
+        r2.step(timeout_now).unwrap();
+        assert_eq!(r2.state, StateRole::Candidate);
+        assert_eq!(r2.term, 2);
+        let votes: Vec<Message> = r2.msg.drain(..).collect();
+        for vote in votes {
+            assert_eq!(vote.context, CAMPAIGN_TRANSFER);
+            let resp = if vote.to == 1 {
+                r1.step(vote).unwrap();
+                r1.msg.pop().unwrap()
+            } else {
+                r3.step(vote).unwrap();
+                r3.msg.pop().unwrap()
+            };
+            assert!(!resp.reject);
+            r2.step(resp).unwrap();
+        }
+        assert_eq!(r2.state, StateRole::Leader);
+        assert_eq!(r1.state, StateRole::Follower);
+    }
+
+    #[test]
+    fn test_leader_transfer_catches_up_transferee() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2]);
+        let (conf2, storage2) = new_test_config(2, vec![1, 2]);
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        r1.become_candidate();
+        r1.become_leader();
+        r1.propose(vec![], b"foo".to_vec()).unwrap();
+        let append = r1.msg.pop().unwrap();
+        assert_eq!(append.msg_type(), MessageType::MsgAppend);
+
+        // The probe carrying the log is still in flight, so the transfer waits
+        // for its answer rather than sending another append.
+        r1.step(new_message(INVALID_ID, MessageType::MsgTransferLeader, Some(2)))
+            .unwrap();
+        assert_eq!(r1.lead_transferee, Some(2));
+        assert!(r1.msg.is_empty());
+        assert!(r1.propose(vec![], b"bar".to_vec()).is_err());
+
+        r2.step(append).unwrap();
+        r1.step(r2.msg.pop().unwrap()).unwrap();
+        let timeout_now = r1.msg.pop().unwrap();
+        assert_eq!(timeout_now.msg_type(), MessageType::MsgTimeoutNow);
+        assert_eq!(timeout_now.to, 2);
+    }
+
+    #[test]
+    fn test_leader_transfer_timeout() {
+        let logger = new_test_logger();
+        let (mut conf, storage) = new_test_config(1, vec![1, 2]);
+        conf.check_quorum = false;
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        r.propose(vec![], b"foo".to_vec()).unwrap();
+
+        r.step(new_message(INVALID_ID, MessageType::MsgTransferLeader, Some(2)))
+            .unwrap();
+        assert_eq!(r.lead_transferee, Some(2));
+        for _ in 0..r.r.election_timeout {
+            r.tick();
+        }
+        assert_eq!(r.lead_transferee, None);
+        assert_eq!(r.state, StateRole::Leader);
+        r.propose(vec![], b"bar".to_vec()).unwrap();
+    }
+
+    fn new_read_index(ctx: &[u8]) -> Message {
+        let mut m = new_message(INVALID_ID, MessageType::MsgReadIndex, None);
+        m.entries = vec![Entry {
+            data: ctx.to_vec(),
+            ..Default::default()
+        }];
+        m
+    }
+
+    #[test]
+    fn test_read_index_single_voter() {
+        let logger = new_test_logger();
+        let (conf, storage) = new_test_config(1, vec![1]);
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        persist(&mut r);
+
+        r.step(new_read_index(b"ctx")).unwrap();
+        assert!(r.msg.is_empty());
+        assert_eq!(
+            r.read_states,
+            vec![ReadState {
+                index: r.raft_log.committed,
+                request_ctx: b"ctx".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_index_confirms_leadership() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2, 3]);
+        let (conf2, storage2) = new_test_config(2, vec![1, 2, 3]);
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        r1.become_candidate();
+        r1.become_leader();
+        r2.become_follower(1, 1);
+
+        // Until its no-op entry commits, the leader's commit index may be stale
+        // and the request has to wait.
+        r1.step(new_read_index(b"ctx")).unwrap();
+        assert!(r1.msg.is_empty());
+        commit_noop(&mut r1, 3);
+        r1.step(new_read_index(b"ctx")).unwrap();
+        assert!(r1.read_states.is_empty());
+        let heartbeats: Vec<Message> = r1.msg.drain(..).collect();
+        assert_eq!(heartbeats.len(), 2);
+        for hb in &heartbeats {
+            assert_eq!(hb.msg_type(), MessageType::MsgHeartbeat);
+            assert_eq!(hb.context, b"ctx");
+        }
+
+        // A single follower ack plus the leader itself makes a quorum.
+        let hb = heartbeats.into_iter().find(|m| m.to == 2).unwrap();
+        r2.step(hb).unwrap();
+        let resp = r2.msg.pop().unwrap();
+        assert_eq!(resp.context, b"ctx");
+        r1.step(resp).unwrap();
+        assert_eq!(r1.read_states.len(), 1);
+        assert_eq!(r1.read_states[0].request_ctx, b"ctx");
+        assert_eq!(r1.read_only.pending_read_count(), 0);
+    }
+
+    #[test]
+    fn test_read_index_forwarded_by_follower() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2]);
+        let (conf2, storage2) = new_test_config(2, vec![1, 2]);
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        r1.become_candidate();
+        r1.become_leader();
+        r2.become_follower(1, 1);
+        persist(&mut r1);
+        r1.bcast_append();
+        r2.step(r1.msg.pop().unwrap()).unwrap();
+        r1.step(r2.msg.pop().unwrap()).unwrap();
+        assert!(r1.commit_to_current_term());
+        r1.msg.clear();
+
+        r2.step(new_read_index(b"ctx")).unwrap();
+        let req = r2.msg.pop().unwrap();
+        assert_eq!(req.msg_type(), MessageType::MsgReadIndex);
+        assert_eq!((req.from, req.to), (2, 1));
+        r1.step(req).unwrap();
+
+        let hb = r1.msg.pop().unwrap();
+        r2.step(hb).unwrap();
+        r1.step(r2.msg.pop().unwrap()).unwrap();
+        assert!(r1.read_states.is_empty());
+
+        let resp = r1.msg.pop().unwrap();
+        assert_eq!(resp.msg_type(), MessageType::MsgReadIndexResp);
+        r2.step(resp).unwrap();
+        assert_eq!(
+            r2.read_states,
+            vec![ReadState {
+                index: r1.raft_log.committed,
+                request_ctx: b"ctx".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_index_lease_based() {
+        let logger = new_test_logger();
+        let (mut conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        conf.read_only_option = ReadOnlyOption::LeaseBased;
+        conf.check_quorum = false;
+        assert!(Raft::new(&conf, storage.clone(), &logger).is_err());
+
+        conf.check_quorum = true;
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        commit_noop(&mut r, 2);
+
+        // Served right away from within the lease, no heartbeat round needed.
+        r.step(new_read_index(b"ctx")).unwrap();
+        assert!(r.msg.is_empty());
+        assert_eq!(r.read_states.len(), 1);
+        assert_eq!(r.read_states[0].request_ctx, b"ctx");
+    }
+
+    #[test]
+    fn test_leader_commits_only_current_term() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2]);
+        storage1
+            .wl()
+            .append(&[new_entry(1, 1), new_entry(2, 1)])
+            .unwrap();
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        r1.become_candidate();
+        r1.become_candidate();
+        r1.become_leader();
+        assert_eq!(r1.term, 2);
+        persist(&mut r1);
+        assert_eq!(r1.raft_log.last_index(), 3);
+
+        // Replicated on both nodes, but entries from an older term are not
+        // committed by counting replicas.
+        let mut resp = new_message(1, MessageType::MsgAppendResponse, Some(2));
+        resp.term = 2;
+        resp.index = 2;
+        r1.step(resp.clone()).unwrap();
+        assert_eq!(r1.prs.get(2).unwrap().matched, 2);
+        assert_eq!(r1.raft_log.committed, 0);
+        assert!(!r1.commit_to_current_term());
+
+        // Committing the no-op entry commits the older entries along with it.
+        resp.index = 3;
+        r1.step(resp).unwrap();
+        assert_eq!(r1.raft_log.committed, 3);
+        assert!(r1.commit_to_current_term());
+    }
+
+    #[test]
+    fn test_progress_probe_then_replicate() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2]);
+        let (conf2, storage2) = new_test_config(2, vec![1, 2]);
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        r1.become_candidate();
+        r1.become_leader();
+        assert_eq!(r1.prs.get(1).unwrap().state, ProgressState::Replicate);
+
+        // Only one probe is outstanding at a time.
+        r1.bcast_append();
+        let probe = r1.msg.pop().unwrap();
+        assert!(r1.prs.get(2).unwrap().is_paused());
+        r1.propose(vec![], b"foo".to_vec()).unwrap();
+        assert!(r1.msg.is_empty());
+
+        // Once the probe is answered the peer is replicated to in a pipeline.
+        r2.step(probe).unwrap();
+        r1.step(r2.msg.pop().unwrap()).unwrap();
+        let pr = r1.prs.get(2).unwrap();
+        assert_eq!(pr.state, ProgressState::Replicate);
+        assert_eq!(pr.matched, 1);
+        let append = r1.msg.pop().unwrap();
+        assert_eq!(append.entries.len(), 1);
+        assert_eq!(r1.prs.get(2).unwrap().next_idx, 3);
+        r1.propose(vec![], b"bar".to_vec()).unwrap();
+        let append = r1.msg.pop().unwrap();
+        assert_eq!((append.index, append.entries.len()), (2, 1));
+        assert_eq!(r1.prs.get(2).unwrap().next_idx, 4);
+
+        // A rejection in replicate mode falls back to probing from the match index.
+        let mut reject = new_message(1, MessageType::MsgAppendResponse, Some(2));
+        reject.term = r1.term;
+        reject.index = 2;
+        reject.reject = true;
+        reject.reject_hint = 1;
+        r1.step(reject).unwrap();
+        let pr = r1.prs.get(2).unwrap();
+        assert_eq!(pr.state, ProgressState::Probe);
+        assert_eq!(pr.next_idx, 2);
+        assert!(pr.is_paused());
+    }
+
+    #[test]
+    fn test_replicate_bounded_by_inflights() {
+        let logger = new_test_logger();
+        let (mut conf, storage) = new_test_config(1, vec![1, 2]);
+        conf.max_inflight_msgs = 2;
+        conf.max_size_per_msg = 3;
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        r.bcast_append();
+        r.msg.clear();
+        let mut resp = new_message(1, MessageType::MsgAppendResponse, Some(2));
+        resp.term = r.term;
+        resp.index = 1;
+        r.step(resp.clone()).unwrap();
+        assert_eq!(r.prs.get(2).unwrap().state, ProgressState::Replicate);
+        r.msg.clear();
+
+        for _ in 0..3 {
+            r.propose(vec![], b"foo".to_vec()).unwrap();
+        }
+        // One entry per message and only two messages in flight.
+        let msgs: Vec<Message> = r.msg.drain(..).collect();
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs.iter().all(|m| m.entries.len() == 1));
+        assert!(r.prs.get(2).unwrap().is_paused());
+
+        // Acknowledging the first append frees a slot for the last entry.
+        resp.index = 2;
+        r.step(resp).unwrap();
+        let m = r.msg.pop().unwrap();
+        assert!(r.msg.is_empty());
+        assert_eq!((m.index, m.entries.len()), (3, 1));
+    }
+
+    fn new_snapshot(index: u64, term: u64, voters: Vec<u64>) -> Snapshot {
+        Snapshot {
+            metadata: Some(SnapshotMetadata {
+                conf_state: Some(ConfState {
+                    voters,
+                    ..Default::default()
+                }),
+                index,
+                term,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_send_snapshot_for_compacted_log() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2]);
+        storage1
+            .wl()
+            .apply_snapshot(new_snapshot(5, 1, vec![1, 2]))
+            .unwrap();
+        let (conf2, storage2) = new_test_config(2, vec![1, 2]);
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        r1.become_candidate();
+        r1.become_leader();
+        r2.become_follower(1, 1);
+
+        // The probe is rejected and the entries the follower needs are compacted.
+        r1.bcast_append();
+        r2.step(r1.msg.pop().unwrap()).unwrap();
+        r1.step(r2.msg.pop().unwrap()).unwrap();
+        let m = r1.msg.pop().unwrap();
+        assert_eq!(m.msg_type(), MessageType::MsgSnapshot);
+        let pr = r1.prs.get(2).unwrap();
+        assert_eq!(pr.state, ProgressState::Snapshot);
+        assert_eq!(pr.pending_snapshot, 5);
+
+        r2.step(m).unwrap();
+        assert_eq!(r2.raft_log.committed, 5);
+        assert_eq!(r2.raft_log.last_index(), 5);
+        assert!(r2.raft_log.unstable_snapshot().is_some());
+        let resp = r2.msg.pop().unwrap();
+        assert_eq!(resp.index, 5);
+
+        // Once the follower has the snapshot, replication goes on from the log.
+        r1.step(resp).unwrap();
+        assert_eq!(r1.prs.get(2).unwrap().matched, 5);
+        let m = r1.msg.pop().unwrap();
+        assert_eq!(m.msg_type(), MessageType::MsgAppend);
+        assert_eq!((m.index, m.entries.len()), (5, 1));
+    }
+
+    #[test]
+    fn test_snapshot_status_and_request() {
+        let logger = new_test_logger();
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(5, 1, vec![1, 2]))
+            .unwrap();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        r.prs.get_mut(2).unwrap().become_snapshot(5);
+
+        // A failed snapshot is retried after the peer is probed again.
+        let mut status = new_message(1, MessageType::MsgSnapStatus, Some(2));
+        status.reject = true;
+        r.step(status).unwrap();
+        let pr = r.prs.get(2).unwrap();
+        assert_eq!(pr.state, ProgressState::Probe);
+        assert_eq!(pr.pending_snapshot, 0);
+        assert!(pr.is_paused());
+
+        // A follower asking for a snapshot gets one even though its log matches.
+        r.prs.get_mut(2).unwrap().resume();
+        let mut req = new_message(1, MessageType::MsgAppendResponse, Some(2));
+        req.term = r.term;
+        req.reject = true;
+        req.request_snapshot = 6;
+        r.step(req).unwrap();
+        let m = r.msg.pop().unwrap();
+        assert_eq!(m.msg_type(), MessageType::MsgSnapshot);
+        assert_eq!(r.prs.get(2).unwrap().state, ProgressState::Snapshot);
+
+        // Only a follower with a leader can request a snapshot.
+        let (conf2, storage2) = new_test_config(2, vec![1, 2]);
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        assert!(r2.request_snapshot().is_err());
+    }
+
+    #[test]
+    fn test_reject_hint_skips_conflicting_term() {
+        let logger = new_test_logger();
+        let (conf1, storage1) = new_test_config(1, vec![1, 2]);
+        let mut ents = vec![new_entry(1, 1), new_entry(2, 1)];
+        ents.extend((3..=7).map(|i| new_entry(i, 3)));
+        storage1.wl().append(&ents).unwrap();
+        // The follower kept a long uncommitted tail from a leader at term 2.
+        let (conf2, storage2) = new_test_config(2, vec![1, 2]);
+        let mut ents = vec![new_entry(1, 1), new_entry(2, 1)];
+        ents.extend((3..=10).map(|i| new_entry(i, 2)));
+        storage2.wl().append(&ents).unwrap();
+
+        let mut r1 = Raft::new(&conf1, storage1, &logger).unwrap();
+        let mut r2 = Raft::new(&conf2, storage2, &logger).unwrap();
+        for _ in 0..3 {
+            r1.become_candidate();
+        }
+        r1.become_leader();
+        r2.become_follower(3, 1);
+
+        r1.bcast_append();
+        let probe = r1.msg.pop().unwrap();
+        assert_eq!((probe.index, probe.log_term), (7, 3));
+        r2.step(probe).unwrap();
+        let resp = r2.msg.pop().unwrap();
+        assert!(resp.reject);
+        assert_eq!((resp.reject_hint, resp.log_term), (7, 2));
+
+        // The leader skips all of its term 3 entries in a single round trip.
+        r1.step(resp).unwrap();
+        assert_eq!(r1.prs.get(2).unwrap().next_idx, 3);
+        let m = r1.msg.pop().unwrap();
+        assert_eq!((m.index, m.log_term), (2, 1));
+        r2.step(m).unwrap();
+        assert!(!r2.msg.pop().unwrap().reject);
+        assert_eq!(r2.raft_log.last_index(), 8);
+        assert_eq!(r2.raft_log.term(7).unwrap(), 3);
+    }
+
+    fn new_test_learner_raft(id: u64) -> Raft<MemStorage> {
+        let (conf, _) = new_test_config(id, vec![]);
+        let storage = MemStorage::new_with_conf_state(ConfState {
+            voters: vec![1, 2],
+            learners: vec![3],
+            ..Default::default()
+        });
+        Raft::new(&conf, storage, &new_test_logger()).unwrap()
+    }
+
+    #[test]
+    fn test_learner_replicated_but_not_counted() {
+        let mut r = new_test_learner_raft(1);
+        r.become_candidate();
+        r.become_leader();
+        persist(&mut r);
+
+        r.bcast_append();
+        let mut to: Vec<u64> = r.msg.drain(..).map(|m| m.to).collect();
+        to.sort();
+        assert_eq!(to, vec![2, 3]);
+        r.step(new_message(INVALID_ID, MessageType::MsgBeat, None))
+            .unwrap();
+        let mut to: Vec<u64> = r.msg.drain(..).map(|m| m.to).collect();
+        to.sort();
+        assert_eq!(to, vec![2, 3]);
+
+        // The learner's ack does not make a quorum.
+        let mut resp = new_message(1, MessageType::MsgAppendResponse, Some(3));
+        resp.term = r.term;
+        resp.index = 1;
+        r.step(resp.clone()).unwrap();
+        assert_eq!(r.raft_log.committed, 0);
+        resp.from = 2;
+        r.step(resp).unwrap();
+        assert_eq!(r.raft_log.committed, 1);
+    }
+
+    #[test]
+    fn test_learner_never_campaigns_or_votes() {
+        let mut r = new_test_learner_raft(3);
+        assert!(!r.promotable());
+        for _ in 0..r.r.max_election_timeout {
+            r.tick();
+        }
+        assert_eq!(r.state, StateRole::Follower);
+        assert!(r.msg.is_empty());
+
+        let mut timeout_now = new_message(3, MessageType::MsgTimeoutNow, Some(1));
+        timeout_now.term = r.term;
+        r.step(timeout_now).unwrap();
+        assert_eq!(r.state, StateRole::Follower);
+        assert!(r.msg.is_empty());
+
+        let mut vote = new_message(3, MessageType::MsgRequestVote, Some(2));
+        vote.term = 1;
+        r.step(vote).unwrap();
+        let resp = r.msg.pop().unwrap();
+        assert_eq!(resp.msg_type(), MessageType::MsgRequestVoteResponse);
+        assert!(resp.reject);
+        assert_eq!(r.vote, INVALID_ID);
+    }
+
+    fn new_conf_change(node_id: u64, change_type: ConfChangeType) -> ConfChange {
+        let mut cc = ConfChange {
+            node_id,
+            ..Default::default()
+        };
+        cc.set_change_type(change_type);
+        cc
+    }
+
+    #[test]
+    fn test_pending_conf_change_blocks_next_one() {
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        // Set to the last index from before the term, so nothing is pending yet.
+        assert_eq!(r.pending_conf_index, 0);
+        commit_noop(&mut r, 2);
+        r.raft_log.applied_to(1);
+
+        let cc = new_conf_change(3, ConfChangeType::AddNode);
+        r.propose_conf_change(vec![], cc.clone()).unwrap();
+        assert_eq!(r.pending_conf_index, 2);
+        let ent = &r
+            .raft_log
+            .entries(2, 3, None, GetEntriesContext::empty(false))
+            .unwrap()[0];
+        assert_eq!(ent.entry_type(), EntryType::EntryConfChange);
+        assert_eq!(ConfChange::decode(ent.data.as_slice()).unwrap(), cc);
+
+        // A second change before the first is applied becomes an empty entry.
+        r.propose_conf_change(vec![], new_conf_change(4, ConfChangeType::AddNode))
+            .unwrap();
+        let ent = &r
+            .raft_log
+            .entries(3, 4, None, GetEntriesContext::empty(false))
+            .unwrap()[0];
+        assert_eq!(ent.entry_type(), EntryType::EntryNormal);
+        assert!(ent.data.is_empty());
+        assert_eq!(r.pending_conf_index, 2);
+    }
+
+    #[test]
+    fn test_apply_conf_change() {
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        commit_noop(&mut r, 2);
+
+        let cs = r
+            .apply_conf_change(&new_conf_change(3, ConfChangeType::AddNode))
+            .unwrap();
+        assert_eq!(cs.voters, vec![1, 2, 3]);
+        assert!(r.prs.get(3).is_some());
+        // The new voter is probed right away.
+        assert_eq!(r.msg.len(), 1);
+        assert_eq!(r.msg[0].to, 3);
+        assert_eq!(r.msg[0].msg_type(), MessageType::MsgAppend);
+
+        let cs = r
+            .apply_conf_change(&new_conf_change(2, ConfChangeType::AddLearnerNode))
+            .unwrap();
+        assert_eq!(cs.voters, vec![1, 3]);
+        assert_eq!(cs.learners, vec![2]);
+
+        let cs = r
+            .apply_conf_change(&new_conf_change(2, ConfChangeType::RemoveNode))
+            .unwrap();
+        assert!(cs.learners.is_empty());
+        assert!(r.prs.get(2).is_none());
+    }
+
+    fn new_conf_change_v2(
+        changes: Vec<(u64, ConfChangeType)>,
+        transition: ConfChangeTransition,
+    ) -> ConfChangeV2 {
+        let mut cc = ConfChangeV2 {
+            changes: changes
+                .into_iter()
+                .map(|(id, ty)| crate::util::new_conf_change_single(id, ty))
+                .collect(),
+            ..Default::default()
+        };
+        cc.set_transition(transition);
+        cc
+    }
+
+    /// Persists the leader's log and acks it from `peer`, committing everything
+    /// in a two node group.
+    fn commit_all(r: &mut Raft<MemStorage>, peer: u64) {
+        persist(r);
+        let mut resp = new_message(r.id, MessageType::MsgAppendResponse, Some(peer));
+        resp.term = r.term;
+        resp.index = r.raft_log.last_index();
+        r.step(resp).unwrap();
+        assert_eq!(r.raft_log.committed, r.raft_log.last_index());
+        r.msg.clear();
+    }
+
+    #[test]
+    fn test_joint_conf_change_auto_leave() {
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        commit_all(&mut r, 2);
+        r.commit_apply(1);
+
+        // Adding 3 and demoting 2 at once needs joint consensus.
+        let cc = new_conf_change_v2(
+            vec![
+                (3, ConfChangeType::AddNode),
+                (2, ConfChangeType::AddLearnerNode),
+            ],
+            ConfChangeTransition::Auto,
+        );
+        assert_eq!(cc.enter_joint(), Some(true));
+        r.propose_conf_change(vec![], cc.clone()).unwrap();
+        let ent = r
+            .raft_log
+            .entries(2, 3, None, GetEntriesContext::empty(false))
+            .unwrap()[0]
+            .clone();
+        assert_eq!(ent.entry_type(), EntryType::EntryConfChangeV2);
+        commit_all(&mut r, 2);
+
+        let cs = r.apply_conf_change(&cc).unwrap();
+        assert_eq!(cs.voters, vec![1, 3]);
+        assert_eq!(cs.voters_outgoing, vec![1, 2]);
+        assert!(cs.learners.is_empty());
+        assert_eq!(cs.learners_next, vec![2]);
+        assert!(cs.auto_leave);
+
+        // Applying the enter entry makes the leader propose the empty change.
+        r.commit_apply(2);
+        assert_eq!(r.raft_log.last_index(), 3);
+        assert_eq!(r.pending_conf_index, 3);
+        let ent = r
+            .raft_log
+            .entries(3, 4, None, GetEntriesContext::empty(false))
+            .unwrap()[0]
+            .clone();
+        assert_eq!(ent.entry_type(), EntryType::EntryConfChangeV2);
+        let leave = ConfChangeV2::decode(ent.data.as_slice()).unwrap();
+        assert!(leave.leave_joint());
+
+        let cs = r.apply_conf_change(&leave).unwrap();
+        assert_eq!(cs.voters, vec![1, 3]);
+        assert!(cs.voters_outgoing.is_empty());
+        assert_eq!(cs.learners, vec![2]);
+        assert!(cs.learners_next.is_empty());
+        assert!(!cs.auto_leave);
+    }
+
+    #[test]
+    fn test_explicit_joint_conf_change() {
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        commit_all(&mut r, 2);
+        r.commit_apply(1);
+
+        // There is no joint configuration to leave yet.
+        r.propose_conf_change(vec![], ConfChangeV2::default())
+            .unwrap();
+        let ent = r
+            .raft_log
+            .entries(2, 3, None, GetEntriesContext::empty(false))
+            .unwrap()[0]
+            .clone();
+        assert_eq!(ent.entry_type(), EntryType::EntryNormal);
+
+        let cc = new_conf_change_v2(
+            vec![(3, ConfChangeType::AddNode)],
+            ConfChangeTransition::Explicit,
+        );
+        r.propose_conf_change(vec![], cc.clone()).unwrap();
+        assert_eq!(r.pending_conf_index, 3);
+        commit_all(&mut r, 2);
+        let cs = r.apply_conf_change(&cc).unwrap();
+        assert_eq!(cs.voters_outgoing, vec![1, 2]);
+        assert!(!cs.auto_leave);
+        r.commit_apply(3);
+        assert_eq!(r.raft_log.last_index(), 3);
+
+        // Further changes are refused until the application leaves the joint state.
+        let add = new_conf_change_v2(
+            vec![(4, ConfChangeType::AddNode)],
+            ConfChangeTransition::Auto,
+        );
+        r.propose_conf_change(vec![], add).unwrap();
+        let ent = r
+            .raft_log
+            .entries(4, 5, None, GetEntriesContext::empty(false))
+            .unwrap()[0]
+            .clone();
+        assert_eq!(ent.entry_type(), EntryType::EntryNormal);
+        r.propose_conf_change(vec![], ConfChangeV2::default())
+            .unwrap();
+        let ent = r
+            .raft_log
+            .entries(5, 6, None, GetEntriesContext::empty(false))
+            .unwrap()[0]
+            .clone();
+        assert_eq!(ent.entry_type(), EntryType::EntryConfChangeV2);
+        assert_eq!(r.pending_conf_index, 5);
+    }
+
+    #[test]
+    fn test_restart_restores_hard_state() {
+        let (mut conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        storage
+            .wl()
+            .append(&[new_entry(1, 1), new_entry(2, 2), new_entry(3, 2)])
+            .unwrap();
+        storage.wl().set_hardstate(HardState {
+            term: 2,
+            vote: 2,
+            commit: 2,
+        });
+        conf.applied = 1;
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage.clone(), &logger).unwrap();
+        assert_eq!((r.term, r.vote), (2, 2));
+        assert_eq!(r.raft_log.committed, 2);
+        assert_eq!(r.raft_log.applied, 1);
+        // Only the entries the state machine has not seen yet are handed out.
+        let ents = r.raft_log.next_entries(None).unwrap();
+        assert_eq!(ents.iter().map(|e| e.index).collect::<Vec<_>>(), vec![2]);
+
+        // The vote of the term is remembered across the restart.
+        let mut vote = new_message(1, MessageType::MsgRequestVote, Some(3));
+        vote.term = 2;
+        vote.index = 3;
+        vote.log_term = 2;
+        r.step(vote).unwrap();
+        assert!(r.msg.pop().unwrap().reject);
+        assert_eq!(r.vote, 2);
+
+        // A commit index beyond the log is refused.
+        storage.wl().set_hardstate(HardState {
+            term: 2,
+            vote: 2,
+            commit: 4,
+        });
+        assert!(Raft::new(&conf, storage, &logger).is_err());
+    }
+
+    #[test]
+    fn test_send_append_waits_for_entries_fetched() {
+        let logger = new_test_logger();
+        let (conf, storage) = new_test_config(1, vec![1, 2]);
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        commit_noop(&mut r, 2);
+        r.propose(vec![], b"foo".to_vec()).unwrap();
+        persist(&mut r);
+        r.msg.clear();
+
+        // Probing from entry 2 has to read it from storage, which fetches it
+        // asynchronously.
+        r.raft_log.storage.wl().trigger_log_unavailable(true);
+        let mut reject = new_message(1, MessageType::MsgAppendResponse, Some(2));
+        reject.term = r.term;
+        reject.index = 2;
+        reject.reject = true;
+        reject.reject_hint = 1;
+        r.step(reject).unwrap();
+        assert!(r.msg.is_empty());
+        assert!(r.prs.get(2).unwrap().awaiting_fetch);
+        let context = r.raft_log.storage.wl().take_get_entries_context().unwrap();
+        match context.0 {
+            GetEntriesFor::SendAppend { to, term, .. } => assert_eq!((to, term), (2, r.term)),
+            _ => panic!("unexpected context {:?}", context),
+        }
+
+        // A heartbeat response doesn't start a second fetch while one is pending.
+        let mut hb_resp = new_message(1, MessageType::MsgHeartbeatResponse, Some(2));
+        hb_resp.term = r.term;
+        r.step(hb_resp).unwrap();
+        assert!(r.msg.iter().all(|m| m.msg_type() != MessageType::MsgAppend));
+        assert!(r.raft_log.storage.wl().take_get_entries_context().is_none());
+        assert!(r.prs.get(2).unwrap().awaiting_fetch);
+
+        // A fetch requested in another term is ignored.
+        r.raft_log.storage.wl().trigger_log_unavailable(false);
+        let stale = GetEntriesContext(GetEntriesFor::SendAppend {
+            to: 2,
+            term: r.term - 1,
+            aggressively: false,
+        });
+        r.on_entries_fetched(stale);
+        assert!(r.msg.iter().all(|m| m.msg_type() != MessageType::MsgAppend));
+        r.msg.clear();
+
+        r.on_entries_fetched(context);
+        assert!(!r.prs.get(2).unwrap().awaiting_fetch);
+        let m = r.msg.pop().unwrap();
+        assert_eq!(m.msg_type(), MessageType::MsgAppend);
+        assert_eq!((m.to, m.index), (2, 1));
+        assert_eq!(m.entries.iter().map(|e| e.index).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_candidate_steps_down_on_heartbeat() {
+        let (conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        let logger = new_test_logger();
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        assert_eq!(r.state, StateRole::Candidate);
+        r.msg.clear();
+
+        // Node 2 won the election of the same term.
+        let mut hb = new_message(1, MessageType::MsgHeartbeat, Some(2));
+        hb.term = r.term;
+        r.step(hb).unwrap();
+        assert_eq!(r.state, StateRole::Follower);
+        assert_eq!(r.leader_id, 2);
+        let resp = r.msg.pop().unwrap();
+        assert_eq!(resp.msg_type(), MessageType::MsgHeartbeatResponse);
+        assert_eq!(resp.to, 2);
+    }
+
+    #[test]
+    fn test_read_index_without_context_is_dropped() {
+        let logger = new_test_logger();
+        let (conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        let mut r = Raft::new(&conf, storage, &logger).unwrap();
+        r.become_candidate();
+        r.become_leader();
+        commit_noop(&mut r, 2);
+
+        let mut m = new_read_index(b"ctx");
+        m.from = 2;
+        m.entries.clear();
+        r.step(m.clone()).unwrap();
+        assert!(r.msg.is_empty());
+        assert_eq!(r.read_only.pending_read_count(), 0);
+
+        // A follower doesn't forward it either.
+        r.become_follower(r.term + 1, 2);
+        r.step(m).unwrap();
+        assert!(r.msg.is_empty());
+    }
+
+    #[test]
+    fn test_restart_refuses_applied_out_of_range() {
+        let logger = new_test_logger();
+        let (mut conf, storage) = new_test_config(1, vec![1, 2, 3]);
+        storage
+            .wl()
+            .append(&[new_entry(1, 1), new_entry(2, 1), new_entry(3, 1)])
+            .unwrap();
+        storage.wl().set_hardstate(HardState {
+            term: 1,
+            vote: 0,
+            commit: 2,
+        });
+        conf.applied = 3;
+        assert!(Raft::new(&conf, storage.clone(), &logger).is_err());
+
+        // Below the compacted index is refused too.
+        storage.wl().compact(3).unwrap();
+        conf.applied = 1;
+        assert!(Raft::new(&conf, storage.clone(), &logger).is_err());
+        conf.applied = 2;
+        let r = Raft::new(&conf, storage, &logger).unwrap();
+        assert_eq!(r.raft_log.applied, 2);
+    }
 }
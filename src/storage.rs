@@ -4,10 +4,13 @@ use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use raftpb::proto::*;
 
 use crate::errors::{Error, Result, StorageError};
+use crate::log_unstable::Unstable;
 use crate::util::limit_size;
 
 use getset::{Getters, Setters};
 
+pub mod file;
+
 /// Holds both the hard state (commit index, vote leader, term) and the configuration state
 /// (Current node IDs)
 #[derive(Debug, Clone, Default, Getters, Setters)]
@@ -54,8 +57,9 @@ impl GetEntriesContext {
     }
 }
 
+/// The reason raft is reading entries from `Storage`, carried by `GetEntriesContext`.
 #[derive(Debug, Clone, Copy)]
-pub(crate) enum GetEntriesFor {
+pub enum GetEntriesFor {
     // for sending entries to followers
     SendAppend {
         /// the peer id to which the entries are going to send
@@ -149,7 +153,8 @@ pub struct MemStorageCore {
     // If it is true, the next snapshot will return a
     // SnapshotTemporarilyUnavailable error.
     trigger_snap_unavailable: bool,
-    // Peers that are fetching entries asynchronously.
+    // If it is true, entries fetched with a context that allows async return a
+    // LogTemporarilyUnavailable error.
     trigger_log_unavailable: bool,
     // Stores get entries context.
     get_entries_context: Option<GetEntriesContext>,
@@ -445,6 +450,9 @@ impl Storage for MemStorage {
             core.get_entries_context = Some(context);
             return Err(Error::Store(StorageError::LogTemporarilyUnavailable));
         }
+        if low == high {
+            return Ok(Vec::new());
+        }
 
         let offset = core.entries[0].index;
         let lo = (low - offset) as usize;
@@ -490,7 +498,7 @@ impl Storage for MemStorage {
             Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable))
         } else {
             let mut snap = core.snapshot();
-            let mut meta = snap.metadata.as_mut().unwrap();
+            let meta = snap.metadata.as_mut().unwrap();
             if meta.index < request_index {
                 meta.index = request_index;
             }
@@ -503,33 +511,72 @@ impl Storage for MemStorage {
 /// and those that are applied to the state machine.
 pub struct RaftLog<T: Storage> {
     pub storage: T,
+
+    /// Contains all unstable entries and snapshot.
+    /// they will be saved into storage.
+    pub unstable: Unstable,
+
     pub committed: u64,
+
+    /// The highest log position that is known to be persisted in stable
+    /// storage. It's used for limiting the upper bound of committed and
+    /// persisted entries.
+    ///
+    /// Invariant: persisted < unstable.offset && applied <= committed
+    pub persisted: u64,
+
     pub applied: u64,
 }
 
 impl<T: Storage> RaftLog<T> {
     pub fn new(storage: T) -> Self {
         let first_index = storage.first_index().unwrap_or(1);
+        let last_index = storage.last_index().unwrap_or(0);
 
         RaftLog {
             storage,
+            unstable: Unstable::new(last_index + 1),
             committed: first_index - 1,
+            persisted: last_index,
             applied: first_index - 1,
         }
     }
 
+    /// Returns the first index in the log. It is either the index after the pending
+    /// snapshot or the first index of storage.
+    pub fn first_index(&self) -> u64 {
+        match self.unstable.maybe_first_index() {
+            Some(idx) => idx,
+            None => self.storage.first_index().unwrap_or(1),
+        }
+    }
+
     pub fn last_index(&self) -> u64 {
-        self.storage.last_index().unwrap_or(0)
+        match self.unstable.maybe_last_index() {
+            Some(idx) => idx,
+            None => self.storage.last_index().unwrap_or(0),
+        }
     }
 
     pub fn term(&self, index: u64) -> Result<u64> {
-        self.storage.term(index)
+        if index > self.last_index() {
+            return Err(Error::Store(StorageError::Unavailable));
+        }
+        match self.unstable.maybe_term(index) {
+            Some(term) => Ok(term),
+            None => self.storage.term(index),
+        }
     }
 
     pub fn last_term(&self) -> u64 {
         self.term(self.last_index()).unwrap_or(0)
     }
 
+    /// Answers the question: Does this index belong to this term?
+    pub fn match_term(&self, idx: u64, term: u64) -> bool {
+        self.term(idx).map(|t| t == term).unwrap_or(false)
+    }
+
     /// is_up_to_date determines if the given (lastIndex, term) is at least
     /// as up-to-date as this log.
     pub fn is_up_to_date(&self, last_index: u64, term: u64) -> bool {
@@ -541,6 +588,223 @@ impl<T: Storage> RaftLog<T> {
         }
     }
 
+    /// Finds the index of the first entry in `ents` whose term does not match the log,
+    /// i.e. the first new or conflicting entry. Returns 0 if every entry already exists.
+    pub fn find_conflict(&self, ents: &[Entry]) -> u64 {
+        for e in ents {
+            if !self.match_term(e.index, e.term) {
+                return e.index;
+            }
+        }
+        0
+    }
+
+    /// find_conflict_by_term takes an (`index`, `term`) pair (indicating a conflicting log
+    /// entry on a leader/follower during an append) and finds the largest index in
+    /// log with log.term <= `term` and log.index <= `index`. If no such index exists
+    /// in the log, the log's first index is returned.
+    ///
+    /// The index provided MUST be equal to or less than self.last_index(). Invalid
+    /// inputs return the input index without a term.
+    ///
+    /// Return (index, term)
+    pub fn find_conflict_by_term(&self, index: u64, term: u64) -> (u64, Option<u64>) {
+        let mut conflict_index = index;
+
+        let last_index = self.last_index();
+        if index > last_index {
+            return (index, None);
+        }
+
+        loop {
+            match self.term(conflict_index) {
+                Ok(t) => {
+                    if t > term {
+                        conflict_index -= 1
+                    } else {
+                        return (conflict_index, Some(t));
+                    }
+                }
+                Err(_) => return (conflict_index, None),
+            }
+        }
+    }
+
+    /// Returns None if the entries cannot be appended. Otherwise,
+    /// it returns Some((conflict_index, last_index)).
+    ///
+    /// # Panics
+    ///
+    /// Panics if it finds a conflicting index less than committed index.
+    pub fn maybe_append(
+        &mut self,
+        idx: u64,
+        term: u64,
+        committed: u64,
+        ents: &[Entry],
+    ) -> Option<(u64, u64)> {
+        if !self.match_term(idx, term) {
+            return None;
+        }
+
+        let last_new_index = idx + ents.len() as u64;
+        let conflict_idx = self.find_conflict(ents);
+        if conflict_idx != 0 {
+            if conflict_idx <= self.committed {
+                panic!(
+                    "entry {} conflict with committed entry {}",
+                    conflict_idx, self.committed
+                )
+            }
+            let start = (conflict_idx - (idx + 1)) as usize;
+            self.append(&ents[start..]);
+            // persisted should be decreased because entries are changed
+            if self.persisted > conflict_idx - 1 {
+                self.persisted = conflict_idx - 1;
+            }
+        }
+        self.commit_to(cmp::min(committed, last_new_index));
+        Some((conflict_idx, last_new_index))
+    }
+
+    /// Appends a set of entries to the unstable list, replacing any entry of the
+    /// log from `ents[0].index` onwards. Returns the new last index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the entries would overwrite a committed entry.
+    pub fn append(&mut self, ents: &[Entry]) -> u64 {
+        if ents.is_empty() {
+            return self.last_index();
+        }
+
+        let after = ents[0].index - 1;
+        if after < self.committed {
+            panic!(
+                "after {} is out of range [committed {}]",
+                after, self.committed
+            )
+        }
+        self.unstable.truncate_and_append(ents);
+        self.last_index()
+    }
+
+    /// Returns the entries that have not been written to storage yet.
+    pub fn unstable_entries(&self) -> &[Entry] {
+        &self.unstable.entries
+    }
+
+    /// Marks the entries up to `idx` as written to storage, as long as the entry at
+    /// `idx` still has the given `term`.
+    pub fn stable_to(&mut self, idx: u64, term: u64) {
+        self.unstable.stable_to(idx, term)
+    }
+
+    /// Attempts to persist the index and term and returns whether it did.
+    pub fn maybe_persist(&mut self, index: u64, term: u64) -> bool {
+        // The same entry may have been truncated and appended again while the old copy
+        // was being written, so passing the term check is not enough: the persisted
+        // index must never move past what has already been handed to storage.
+        let first_update_index = match &self.unstable.snapshot {
+            Some(s) => s.metadata.as_ref().map_or(0, |m| m.index),
+            None => self.unstable.offset,
+        };
+        if index > self.persisted
+            && index < first_update_index
+            && self.storage.term(index).is_ok_and(|t| t == term)
+        {
+            self.persisted = index;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Attempts to persist the snapshot and returns whether it did.
+    pub fn maybe_persist_snap(&mut self, index: u64) -> bool {
+        if index > self.persisted {
+            // commit index should not be less than snapshot's index
+            if index > self.committed {
+                panic!(
+                    "snapshot's index {} > committed {}",
+                    index, self.committed
+                )
+            }
+            // All of the indexes of later entries must be greater than snapshot's index
+            if index >= self.unstable.offset {
+                panic!(
+                    "snapshot's index {} >= offset {}",
+                    index, self.unstable.offset
+                );
+            }
+            self.persisted = index;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns all the available entries for execution.
+    /// If applied is smaller than the index of snapshot, it returns all committed
+    /// entries after the index of snapshot.
+    pub fn next_entries(&self, max_size: Option<u64>) -> Option<Vec<Entry>> {
+        let offset = cmp::max(self.applied + 1, self.first_index());
+        let high = self.committed + 1;
+        if high > offset {
+            let context = GetEntriesContext(GetEntriesFor::GenReady);
+            match self.entries(offset, high, max_size, context) {
+                Ok(vec) => return Some(vec),
+                Err(e) => panic!("unexpected error when getting entries to apply: {:?}", e),
+            }
+        }
+        None
+    }
+
+    /// Returns whether there are new entries.
+    pub fn has_next_entries(&self) -> bool {
+        let offset = cmp::max(self.applied + 1, self.first_index());
+        self.committed + 1 > offset
+    }
+
+    /// Returns the pending snapshot that has not been written to storage yet.
+    pub fn unstable_snapshot(&self) -> &Option<Snapshot> {
+        &self.unstable.snapshot
+    }
+
+    /// Marks the pending snapshot at `idx` as written to storage.
+    pub fn stable_snap_to(&mut self, idx: u64) {
+        self.unstable.stable_snap_to(idx)
+    }
+
+    /// Returns the current snapshot, preferring the pending one if it is recent
+    /// enough for `request_index`.
+    pub fn snapshot(&self, request_index: u64, to: u64) -> Result<Snapshot> {
+        if let Some(snap) = &self.unstable.snapshot {
+            if snap.metadata.as_ref().map_or(0, |m| m.index) >= request_index {
+                return Ok(snap.clone());
+            }
+        }
+        self.storage.snapshot(request_index, to)
+    }
+
+    /// Restores the snapshot to the current log, dropping every entry. The
+    /// snapshot is held as unstable until the application persists it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot is older than the commit index.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let index = snapshot.metadata.as_ref().map_or(0, |m| m.index);
+        assert!(index >= self.committed, "{} < {}", index, self.committed);
+        // If `persisted` is greater than `index`, set it to `index` is not necessary but
+        // still correct.
+        if self.persisted > index {
+            self.persisted = index;
+        }
+        self.committed = index;
+        self.unstable.restore(snapshot);
+    }
+
     pub fn commit_to(&mut self, to_commit: u64) {
         if self.committed < to_commit {
             if self.last_index() < to_commit {
@@ -554,6 +818,19 @@ impl<T: Storage> RaftLog<T> {
         }
     }
 
+    /// Attempts to commit the index and term and returns whether it did.
+    ///
+    /// Only entries from `term` can be committed by counting replicas; older
+    /// entries become committed indirectly along with them.
+    pub fn maybe_commit(&mut self, max_index: u64, term: u64) -> bool {
+        if max_index > self.committed && self.term(max_index).is_ok_and(|t| t == term) {
+            self.commit_to(max_index);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn applied_to(&mut self, index: u64) {
         if index == 0 {
             return;
@@ -567,10 +844,207 @@ impl<T: Storage> RaftLog<T> {
         self.applied = index;
     }
 
-    pub fn entries(&self, low: u64, high: u64, max_size: Option<u64>) -> Result<Vec<Entry>> {
+    /// Returns the entries in `[low, high)`, limited by `max_size`. The part that is
+    /// already stable is read from the storage with `context`.
+    pub fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: Option<u64>,
+        context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
+        self.must_check_outofbounds(low, high)?;
         if low == high {
             return Ok(Vec::new());
         }
-        self.storage.entries(low, high, max_size, GetEntriesContext::empty(false))
+
+        let mut ents = vec![];
+        if low < self.unstable.offset {
+            let unstable_high = cmp::min(high, self.unstable.offset);
+            ents = self.storage.entries(low, unstable_high, max_size, context)?;
+            // Storage may have cut the batch short because of `max_size`.
+            if (ents.len() as u64) < unstable_high - low {
+                return Ok(ents);
+            }
+        }
+
+        if high > self.unstable.offset {
+            let offset = self.unstable.offset;
+            let unstable = self.unstable.slice(cmp::max(low, offset), high);
+            ents.extend_from_slice(unstable);
+            limit_size(&mut ents, max_size);
+        }
+        Ok(ents)
+    }
+
+    fn must_check_outofbounds(&self, low: u64, high: u64) -> Result<()> {
+        if low > high {
+            panic!("invalid slice {} > {}", low, high)
+        }
+        let first_index = self.first_index();
+        if low < first_index {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+
+        if high > self.last_index() + 1 {
+            panic!(
+                "slice[{}, {}] out of bound[{}, {}]",
+                low,
+                high,
+                first_index,
+                self.last_index()
+            )
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            ..Default::default()
+        }
+    }
+
+    fn new_snapshot(index: u64, term: u64, voters: Vec<u64>) -> Snapshot {
+        Snapshot {
+            metadata: Some(SnapshotMetadata {
+                index,
+                term,
+                conf_state: Some(ConfState {
+                    voters,
+                    ..Default::default()
+                }),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// A storage compacted up to index 3 (term 3) holding entries 4, 5 and 6.
+    fn new_storage() -> MemStorage {
+        let storage = MemStorage::new();
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(3, 3, vec![1, 2, 3]))
+            .unwrap();
+        storage
+            .wl()
+            .append(&[new_entry(4, 4), new_entry(5, 5), new_entry(6, 6)])
+            .unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_storage_term_and_entries() {
+        let storage = new_storage();
+        assert_eq!((storage.first_index().unwrap(), storage.last_index().unwrap()), (4, 6));
+        assert!(matches!(
+            storage.term(2),
+            Err(Error::Store(StorageError::Compacted))
+        ));
+        assert_eq!(storage.term(3).unwrap(), 3);
+        assert_eq!(storage.term(5).unwrap(), 5);
+        assert!(matches!(
+            storage.term(7),
+            Err(Error::Store(StorageError::Unavailable))
+        ));
+
+        let ctx = GetEntriesContext::empty(false);
+        assert!(matches!(
+            storage.entries(3, 5, None, ctx),
+            Err(Error::Store(StorageError::Compacted))
+        ));
+        assert!(storage.entries(4, 4, None, ctx).unwrap().is_empty());
+        let ents = storage.entries(4, 7, None, ctx).unwrap();
+        assert_eq!(ents, vec![new_entry(4, 4), new_entry(5, 5), new_entry(6, 6)]);
+
+        let ents: Vec<Entry> = (7..10)
+            .map(|i| Entry {
+                data: b"abc".to_vec(),
+                ..new_entry(i, 6)
+            })
+            .collect();
+        storage.wl().append(&ents).unwrap();
+        assert_eq!(storage.entries(7, 10, Some(6), ctx).unwrap().len(), 2);
+        // At least one entry is returned however small the limit is.
+        assert_eq!(storage.entries(7, 10, Some(1), ctx).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_storage_append_and_compact() {
+        let storage = new_storage();
+        // Conflicting entries replace the tail of the log.
+        storage
+            .wl()
+            .append(&[new_entry(5, 6), new_entry(6, 6), new_entry(7, 6)])
+            .unwrap();
+        assert_eq!(storage.term(5).unwrap(), 6);
+        assert_eq!(storage.last_index().unwrap(), 7);
+
+        storage.wl().compact(6).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 6);
+        assert!(matches!(
+            storage.term(5),
+            Err(Error::Store(StorageError::Compacted))
+        ));
+        // Compacting below the first index is a no-op.
+        storage.wl().compact(2).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 6);
+
+        assert!(matches!(
+            storage.wl().apply_snapshot(new_snapshot(4, 4, vec![1])),
+            Err(Error::Store(StorageError::SnapshotOutOfDate))
+        ));
+    }
+
+    #[test]
+    fn test_storage_shared_state_and_snapshot() {
+        let storage = new_storage();
+        let other = storage.clone();
+        other.wl().commit_to(5).unwrap();
+        let state = storage.initial_state().unwrap();
+        assert!(state.initialized());
+        assert_eq!(state.hard_state.commit, 5);
+        assert_eq!(state.conf_state.voters, vec![1, 2, 3]);
+
+        let snap = storage.snapshot(0, 2).unwrap();
+        let meta = snap.metadata.unwrap();
+        assert_eq!((meta.index, meta.term), (5, 5));
+        // The snapshot is never older than the requested index.
+        assert_eq!(storage.snapshot(6, 2).unwrap().metadata.unwrap().index, 6);
+    }
+
+    #[test]
+    fn test_storage_unavailable_hooks() {
+        let storage = new_storage();
+        storage.wl().trigger_snap_unavailable();
+        assert!(matches!(
+            storage.snapshot(0, 2),
+            Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable))
+        ));
+        // The hook only fails the next call.
+        assert!(storage.snapshot(0, 2).is_ok());
+
+        storage.wl().trigger_log_unavailable(true);
+        assert!(storage
+            .entries(4, 5, None, GetEntriesContext::empty(false))
+            .is_ok());
+        assert!(storage.wl().take_get_entries_context().is_none());
+        assert!(matches!(
+            storage.entries(4, 5, None, GetEntriesContext::empty(true)),
+            Err(Error::Store(StorageError::LogTemporarilyUnavailable))
+        ));
+        let ctx = storage.wl().take_get_entries_context().unwrap();
+        assert!(ctx.can_async());
+
+        storage.wl().trigger_log_unavailable(false);
+        assert!(storage
+            .entries(4, 5, None, GetEntriesContext::empty(true))
+            .is_ok());
     }
 }
@@ -0,0 +1,1225 @@
+//! A durable `Storage` keeping the raft log in files.
+//!
+//! The directory passed to `FileStorage::open` holds:
+//!
+//! - `wal/<first index>.log`: append-only segments with the log entries. A new
+//!   segment is started once the current one grows past `segment_size`.
+//! - `meta`: the `HardState`, the `ConfState` and the index and term the log is
+//!   compacted to. It is replaced atomically on every update.
+//! - `snap/<index>-<term>.snap`: the latest snapshot.
+//!
+//! Every record, in segments as well as in the other files, is framed as
+//! `[len: u32][crc32: u32][payload]` in little endian, the payload being a
+//! prost-encoded message. A record that fails its checksum at the end of the
+//! last segment is a torn write and is truncated away when the storage is opened.
+//!
+//! See `writer::WalWriter` for writing the log of many callers with one fsync.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use prost::Message as ProstMessage;
+use raftpb::proto::{ConfState, Entry, HardState, Snapshot, SnapshotMetadata};
+
+use super::{GetEntriesContext, RaftState, Storage};
+use crate::errors::{Error, Result, StorageError};
+
+pub mod writer;
+
+const WAL_DIR: &str = "wal";
+const SNAP_DIR: &str = "snap";
+const META_FILE: &str = "meta";
+const SEGMENT_EXT: &str = "log";
+const SNAP_EXT: &str = "snap";
+const TMP_EXT: &str = "tmp";
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// Decides when `FileStorage` flushes writes to disk with fsync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every append and metadata update, so nothing that has been
+    /// acknowledged can be lost.
+    Always,
+    /// Sync appends at most once per interval. Writes in between may be lost on a
+    /// crash; `FileStorageCore::sync` forces a sync.
+    Batch(Duration),
+    /// Leave syncing to the operating system. Only suitable for tests and benchmarks.
+    Never,
+}
+
+/// Settings of a `FileStorage`.
+#[derive(Debug, Clone)]
+pub struct FileStorageConfig {
+    /// A new segment is started once the current one reaches this size in bytes.
+    pub segment_size: u64,
+
+    /// When appends and metadata updates are synced to disk.
+    pub sync_policy: SyncPolicy,
+}
+
+impl Default for FileStorageConfig {
+    fn default() -> Self {
+        Self {
+            segment_size: 64 * 1024 * 1024,
+            sync_policy: SyncPolicy::Always,
+        }
+    }
+}
+
+/// An append-only file holding the entries from `first_index` on.
+struct Segment {
+    first_index: u64,
+    path: PathBuf,
+    // offsets[i] is the position of the record of entry `first_index + i`.
+    offsets: Vec<u64>,
+    terms: Vec<u64>,
+    size: u64,
+}
+
+impl Segment {
+    fn new(first_index: u64, path: PathBuf) -> Segment {
+        Segment {
+            first_index,
+            path,
+            offsets: Vec::new(),
+            terms: Vec::new(),
+            size: 0,
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.first_index + self.terms.len() as u64 - 1
+    }
+
+    fn term(&self, idx: u64) -> u64 {
+        self.terms[(idx - self.first_index) as usize]
+    }
+
+    fn offset(&self, idx: u64) -> u64 {
+        self.offsets[(idx - self.first_index) as usize]
+    }
+
+    fn push(&mut self, term: u64, len: u64) {
+        self.offsets.push(self.size);
+        self.terms.push(term);
+        self.size += len;
+    }
+}
+
+/// The File Storage Core instance holds the actual state of the storage struct. To access
+/// this value, use the `rl` and `wl` functions on the main FileStorage implementation.
+pub struct FileStorageCore {
+    dir: PathBuf,
+    config: FileStorageConfig,
+    raft_state: RaftState,
+    // The index and term of the last compacted entry, i.e. the one before the first index.
+    truncated_index: u64,
+    truncated_term: u64,
+    // Ordered by index. Only the last one is written to.
+    segments: Vec<Segment>,
+    // The last segment, opened for appending.
+    active: Option<File>,
+    // Whether the active segment has writes that are not synced yet.
+    dirty: bool,
+    last_sync: Instant,
+    // Index and path of the latest snapshot.
+    snapshot_file: Option<(u64, PathBuf)>,
+    // Entries following the segments that are readable but not written yet.
+    staged: Vec<Entry>,
+    // The HardState in the metadata file. It lags behind a staged HardState until
+    // the entries written before it are synced.
+    meta_hard_state: HardState,
+}
+
+impl FileStorageCore {
+    fn open(dir: &Path, config: FileStorageConfig) -> Result<FileStorageCore> {
+        fs::create_dir_all(dir.join(WAL_DIR))?;
+        fs::create_dir_all(dir.join(SNAP_DIR))?;
+        let mut core = FileStorageCore {
+            dir: dir.to_path_buf(),
+            config,
+            raft_state: RaftState::default(),
+            truncated_index: 0,
+            truncated_term: 0,
+            segments: Vec::new(),
+            active: None,
+            dirty: false,
+            last_sync: Instant::now(),
+            snapshot_file: None,
+            staged: Vec::new(),
+            meta_hard_state: HardState::default(),
+        };
+
+        let meta_path = dir.join(META_FILE);
+        if meta_path.exists() {
+            let payload = read_record_file(&meta_path)?;
+            let mut buf = payload.as_slice();
+            let hs = HardState::decode_length_delimited(&mut buf).map_err(corrupted)?;
+            let meta = SnapshotMetadata::decode_length_delimited(&mut buf).map_err(corrupted)?;
+            core.meta_hard_state = hs.clone();
+            core.raft_state = RaftState::new(hs, meta.conf_state.unwrap_or_default());
+            core.truncated_index = meta.index;
+            core.truncated_term = meta.term;
+        }
+        core.recover_snapshot()?;
+        core.recover_segments()?;
+        Ok(core)
+    }
+
+    /// Finds the latest snapshot and removes older ones.
+    fn recover_snapshot(&mut self) -> Result<()> {
+        let mut snaps = Vec::new();
+        for (stem, path) in list_files(&self.dir.join(SNAP_DIR), SNAP_EXT)? {
+            match stem.split_once('-').and_then(|(i, _)| i.parse::<u64>().ok()) {
+                Some(index) => snaps.push((index, path)),
+                None => return Err(corrupted(format!("bad snapshot name {}", path.display()))),
+            }
+        }
+        snaps.sort_unstable();
+        let (index, path) = match snaps.pop() {
+            Some(snap) => snap,
+            None => return Ok(()),
+        };
+        for (_, stale) in snaps {
+            fs::remove_file(stale)?;
+        }
+        self.snapshot_file = Some((index, path));
+        Ok(())
+    }
+
+    /// Loads the index of every segment, truncating a torn write at the end of the
+    /// log and dropping segments that are compacted away.
+    fn recover_segments(&mut self) -> Result<()> {
+        let mut paths = Vec::new();
+        for (stem, path) in list_files(&self.dir.join(WAL_DIR), SEGMENT_EXT)? {
+            match stem.parse::<u64>() {
+                Ok(first_index) => paths.push((first_index, path)),
+                Err(_) => return Err(corrupted(format!("bad segment name {}", path.display()))),
+            }
+        }
+        paths.sort_unstable();
+
+        let count = paths.len();
+        let mut prev_last = None;
+        let mut segments = Vec::with_capacity(count);
+        for (i, (first_index, path)) in paths.into_iter().enumerate() {
+            if let Some(prev_last) = prev_last {
+                if first_index != prev_last + 1 {
+                    return Err(corrupted(format!("gap before segment {}", path.display())));
+                }
+            }
+            let buf = fs::read(&path)?;
+            let mut seg = Segment::new(first_index, path);
+            while (seg.size as usize) < buf.len() {
+                let payload = match decode_record(&buf[seg.size as usize..]) {
+                    Some(payload) => payload,
+                    None if i + 1 == count => {
+                        // A torn write at the tail of the log. Nothing after it was
+                        // acknowledged, so it is safe to drop.
+                        let file = OpenOptions::new().write(true).open(&seg.path)?;
+                        file.set_len(seg.size)?;
+                        file.sync_all()?;
+                        break;
+                    }
+                    None => {
+                        return Err(corrupted(format!(
+                            "bad record at offset {} in {}",
+                            seg.size,
+                            seg.path.display()
+                        )))
+                    }
+                };
+                let entry = Entry::decode(payload).map_err(corrupted)?;
+                if entry.index != seg.first_index + seg.terms.len() as u64 {
+                    return Err(corrupted(format!(
+                        "unexpected entry {} in {}",
+                        entry.index,
+                        seg.path.display()
+                    )));
+                }
+                seg.push(entry.term, RECORD_HEADER_SIZE + payload.len() as u64);
+            }
+            prev_last = Some(seg.last_index());
+            segments.push(seg);
+        }
+
+        // If the log disagrees with the compacted entry, it was overwritten by a
+        // snapshot right before a crash and none of it can be kept.
+        let (index, term) = (self.truncated_index, self.truncated_term);
+        let diverged = segments
+            .iter()
+            .find(|seg| seg.first_index <= index && index <= seg.last_index())
+            .is_some_and(|seg| seg.term(index) != term);
+        for seg in segments {
+            if diverged || seg.terms.is_empty() || seg.last_index() <= index {
+                // Besides, drop empty segments and the ones left behind by a
+                // compaction that did not finish.
+                fs::remove_file(&seg.path)?;
+                continue;
+            }
+            self.segments.push(seg);
+        }
+        if self.segments.first().is_some_and(|seg| seg.first_index > index + 1) {
+            return Err(corrupted(format!("log starts after compacted index {}", index)));
+        }
+        if let Some(last) = self.segments.last() {
+            self.active = Some(OpenOptions::new().append(true).open(&last.path)?);
+        }
+        Ok(())
+    }
+
+    /// Saves the current HardState, along with the staged entries.
+    pub fn set_hardstate(&mut self, hs: HardState) -> Result<()> {
+        self.stage_hardstate(hs);
+        self.flush()
+    }
+
+    /// Sets the HardState, which is saved by the next `flush`.
+    pub fn stage_hardstate(&mut self, hs: HardState) {
+        self.raft_state.hard_state = hs;
+    }
+
+    /// Get the hard state.
+    pub fn hard_state(&self) -> &HardState {
+        &self.raft_state.hard_state
+    }
+
+    /// Saves the current conf state.
+    pub fn set_conf_state(&mut self, cs: ConfState) -> Result<()> {
+        self.raft_state.conf_state = cs;
+        self.persist_meta()
+    }
+
+    fn first_index(&self) -> u64 {
+        self.truncated_index + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        match self.staged.last() {
+            Some(e) => e.index,
+            None => self.written_last_index(),
+        }
+    }
+
+    fn written_last_index(&self) -> u64 {
+        match self.segments.last() {
+            Some(seg) => seg.last_index(),
+            None => self.truncated_index,
+        }
+    }
+
+    fn term(&self, idx: u64) -> Result<u64> {
+        if idx == self.truncated_index {
+            return Ok(self.truncated_term);
+        }
+        if idx < self.first_index() {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        if idx > self.last_index() {
+            return Err(Error::Store(StorageError::Unavailable));
+        }
+        if idx > self.written_last_index() {
+            return Ok(self.staged[(idx - self.staged[0].index) as usize].term);
+        }
+        Ok(self.segment_of(idx).term(idx))
+    }
+
+    fn segment_of(&self, idx: u64) -> &Segment {
+        let i = self.segments.partition_point(|seg| seg.last_index() < idx);
+        &self.segments[i]
+    }
+
+    /// Reads the entries in `[low, high)` from the segments and the staged entries,
+    /// stopping once `max_size` is exceeded.
+    fn read_entries(&self, low: u64, high: u64, max_size: Option<u64>) -> Result<Vec<Entry>> {
+        let mut ents = Vec::with_capacity((high - low) as usize);
+        if max_size == Some(0) {
+            return Ok(ents);
+        }
+        let mut size = 0;
+        let mut idx = low;
+        let mut push = |entry: Entry, ents: &mut Vec<Entry>| {
+            size += entry.data.len() as u64;
+            if max_size.is_some_and(|max| size > max) && !ents.is_empty() {
+                return false;
+            }
+            ents.push(entry);
+            true
+        };
+        let first = self.segments.partition_point(|seg| seg.last_index() < low);
+        for seg in &self.segments[first..] {
+            if idx >= high {
+                break;
+            }
+            let mut reader = BufReader::new(File::open(&seg.path)?);
+            reader.seek(SeekFrom::Start(seg.offset(idx)))?;
+            let end = cmp::min(high, seg.last_index() + 1);
+            while idx < end {
+                if !push(read_entry(&mut reader, &seg.path)?, &mut ents) {
+                    return Ok(ents);
+                }
+                idx += 1;
+            }
+        }
+        if idx < high {
+            let offset = self.staged[0].index;
+            for e in &self.staged[(idx - offset) as usize..(high - offset) as usize] {
+                if !push(e.clone(), &mut ents) {
+                    break;
+                }
+            }
+        }
+        Ok(ents)
+    }
+
+    /// Append the new entries to storage, replacing any conflicting entries after
+    /// `ents[0].index`. They are synced according to the `SyncPolicy`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ents` contains compacted entries, or there's a gap between `ents` and the last
+    /// received entry in the storage.
+    pub fn append(&mut self, ents: &[Entry]) -> Result<()> {
+        self.stage(ents)?;
+        self.flush()
+    }
+
+    /// Like `append`, except that the entries are only written by the next `flush`.
+    /// They can be read right away.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ents` contains compacted entries, or there's a gap between `ents` and the last
+    /// received entry in the storage.
+    pub fn stage(&mut self, ents: &[Entry]) -> Result<()> {
+        if ents.is_empty() {
+            return Ok(());
+        }
+        if self.first_index() > ents[0].index {
+            panic!(
+                "overwrite compacted raft logs, compacted: {}, append: {}",
+                self.first_index() - 1,
+                ents[0].index,
+            );
+        }
+        if self.last_index() + 1 < ents[0].index {
+            panic!(
+                "raft logs should be continuous, last index: {}, new appended: {}",
+                self.last_index(),
+                ents[0].index,
+            );
+        }
+        if ents[0].index <= self.written_last_index() {
+            self.staged.clear();
+            self.truncate_from(ents[0].index)?;
+        } else if let Some(first) = self.staged.first() {
+            let keep = (ents[0].index - first.index) as usize;
+            self.staged.truncate(keep);
+        }
+        self.staged.extend_from_slice(ents);
+        Ok(())
+    }
+
+    /// Writes the staged entries with a single write, then the staged HardState.
+    ///
+    /// The HardState may commit the entries it comes with, so the entries are
+    /// synced before the metadata is replaced, unless the `SyncPolicy` is `Never`.
+    /// Otherwise the entries are synced according to the `SyncPolicy`.
+    pub fn flush(&mut self) -> Result<()> {
+        match self.write_staged()? {
+            Some(hs) => {
+                if self.should_sync() {
+                    self.sync()?;
+                }
+                self.persist_hardstate(hs)
+            }
+            None => self.maybe_sync(),
+        }
+    }
+
+    /// Writes the staged entries with a single write per segment without syncing
+    /// them, and returns the staged HardState if it differs from the saved one. It
+    /// must only be passed to `persist_hardstate` once the entries are synced.
+    ///
+    /// The entries stay staged until they are written, so a failed call can be
+    /// retried.
+    pub fn write_staged(&mut self) -> Result<Option<HardState>> {
+        while !self.staged.is_empty() {
+            let full = self
+                .segments
+                .last()
+                .is_none_or(|seg| seg.size >= self.config.segment_size);
+            if self.active.is_none() || full {
+                self.rotate(self.staged[0].index)?;
+            }
+            let mut size = self.segments.last().unwrap().size;
+            let mut buf = Vec::new();
+            let mut records = Vec::new();
+            for e in &self.staged {
+                if size >= self.config.segment_size {
+                    break;
+                }
+                let start = buf.len();
+                encode_record(&e.encode_to_vec(), &mut buf);
+                let len = (buf.len() - start) as u64;
+                records.push((e.term, len));
+                size += len;
+            }
+            self.write_active(&buf)?;
+            let seg = self.segments.last_mut().unwrap();
+            for &(term, len) in &records {
+                seg.push(term, len);
+            }
+            self.staged.drain(..records.len());
+        }
+        let hs = &self.raft_state.hard_state;
+        Ok((*hs != self.meta_hard_state).then(|| hs.clone()))
+    }
+
+    /// Replaces the HardState in the metadata with `hs`, unless the saved one is
+    /// newer, as happens when a snapshot is applied while the entries of `hs` are
+    /// being synced.
+    pub fn persist_hardstate(&mut self, hs: HardState) -> Result<()> {
+        let saved = &self.meta_hard_state;
+        if hs.term < saved.term
+            || hs.commit < saved.commit
+            || (hs.term == saved.term && hs.vote == 0 && saved.vote != 0)
+        {
+            return Ok(());
+        }
+        self.meta_hard_state = hs;
+        self.persist_meta()
+    }
+
+    /// Returns a handle on the segment being appended to if the `SyncPolicy` calls
+    /// for a sync, as `flush` would, so that a writer can sync it without holding the
+    /// storage lock. The segment is considered synced from then on. `hardstate`
+    /// tells whether a HardState is saved after the sync, in which case the entries
+    /// are synced unless the policy is `Never`. Segments before the active one are
+    /// already synced, unless the policy is `Never`.
+    pub fn start_sync(&mut self, hardstate: bool) -> Result<Option<File>> {
+        let due = match self.config.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Batch(interval) if !hardstate => self.last_sync.elapsed() >= interval,
+            _ => true,
+        };
+        if !due {
+            return Ok(None);
+        }
+        let file = match (&self.active, self.dirty) {
+            (Some(file), true) => Some(file.try_clone()?),
+            _ => None,
+        };
+        self.dirty = false;
+        self.last_sync = Instant::now();
+        Ok(file)
+    }
+
+    /// Discards all log entries prior to compact_index. Segments holding only
+    /// discarded entries are deleted, the one holding `compact_index` is kept.
+    /// It is the application's responsibility to not attempt to compact an index
+    /// greater than RaftLog.applied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `compact_index` is higher than `Storage::last_index(&self) + 1`.
+    pub fn compact(&mut self, compact_index: u64) -> Result<()> {
+        if compact_index <= self.first_index() {
+            // Don't need to treat this case as an error.
+            return Ok(());
+        }
+        if compact_index > self.last_index() + 1 {
+            panic!(
+                "compact not received raft logs: {}, last index: {}",
+                compact_index,
+                self.last_index()
+            );
+        }
+
+        self.truncated_term = self.term(compact_index - 1)?;
+        self.truncated_index = compact_index - 1;
+        self.staged.retain(|e| e.index >= compact_index);
+        self.persist_meta()?;
+        let obsolete = self
+            .segments
+            .partition_point(|seg| seg.last_index() < compact_index);
+        if obsolete == self.segments.len() {
+            self.active = None;
+        }
+        for seg in self.segments.drain(..obsolete) {
+            fs::remove_file(&seg.path)?;
+        }
+        self.sync_wal_dir()
+    }
+
+    /// Overwrites the contents of this Storage object with those of the given snapshot.
+    /// The snapshot is saved, so it is served to followers until a newer one is created.
+    pub fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        let meta = snapshot.metadata.clone().unwrap();
+        if self.first_index() > meta.index {
+            return Err(Error::Store(StorageError::SnapshotOutOfDate));
+        }
+
+        let hs = &mut self.raft_state.hard_state;
+        hs.term = cmp::max(hs.term, meta.term);
+        hs.commit = meta.index;
+        self.raft_state.conf_state = meta.conf_state.unwrap_or_default();
+        self.truncated_index = meta.index;
+        self.truncated_term = meta.term;
+        self.meta_hard_state = self.raft_state.hard_state.clone();
+        // The snapshot is saved before the metadata points past the log, so that a
+        // crash in between leaves the old log, with the new snapshot ready to serve.
+        // Once the metadata is replaced, opening the storage drops any entry left
+        // over that conflicts with the snapshot.
+        self.save_snapshot(&snapshot)?;
+        self.persist_meta()?;
+        self.staged.clear();
+        self.remove_segments()
+    }
+
+    /// Creates a snapshot of the state machine at `index` holding `data`, which can
+    /// then be sent to followers. `cs` defaults to the current `ConfState`. The log is
+    /// left untouched; call `compact` to discard the entries the snapshot covers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not committed.
+    pub fn create_snapshot(
+        &mut self,
+        index: u64,
+        cs: Option<ConfState>,
+        data: Vec<u8>,
+    ) -> Result<Snapshot> {
+        if self.snapshot_file.as_ref().is_some_and(|(i, _)| index <= *i) {
+            return Err(Error::Store(StorageError::SnapshotOutOfDate));
+        }
+        if index > self.raft_state.hard_state.commit {
+            panic!(
+                "snapshot {} is out of bound, commit: {}",
+                index, self.raft_state.hard_state.commit
+            );
+        }
+        let snapshot = Snapshot {
+            data,
+            metadata: Some(SnapshotMetadata {
+                conf_state: Some(cs.unwrap_or_else(|| self.raft_state.conf_state.clone())),
+                index,
+                term: self.term(index)?,
+            }),
+        };
+        self.save_snapshot(&snapshot)?;
+        Ok(snapshot)
+    }
+
+    /// Syncs the appended entries to disk, whatever the `SyncPolicy`.
+    pub fn sync(&mut self) -> Result<()> {
+        if let (Some(file), true) = (&self.active, self.dirty) {
+            file.sync_data()?;
+        }
+        self.dirty = false;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn maybe_sync(&mut self) -> Result<()> {
+        match self.config.sync_policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Batch(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn should_sync(&self) -> bool {
+        self.config.sync_policy != SyncPolicy::Never
+    }
+
+    /// Appends `buf` to the active segment. On failure the segment is cut back to
+    /// its last complete record, so that the write can be retried.
+    fn write_active(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let file = self.active.as_mut().unwrap();
+        if let Err(e) = file.write_all(buf) {
+            let _ = file.set_len(self.segments.last().unwrap().size);
+            return Err(e.into());
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Starts a new segment holding the entries from `first_index` on. The previous
+    /// segment is synced first, so that only the last one can end with a torn write.
+    fn rotate(&mut self, first_index: u64) -> Result<()> {
+        if self.should_sync() {
+            self.sync()?;
+        }
+        let wal_dir = self.dir.join(WAL_DIR);
+        let path = wal_dir.join(format!("{:020}.{}", first_index, SEGMENT_EXT));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if self.should_sync() {
+            sync_dir(&wal_dir)?;
+        }
+        self.segments.push(Segment::new(first_index, path));
+        self.active = Some(file);
+        Ok(())
+    }
+
+    /// Removes the entries from `index` on, deleting the segments that start after it.
+    /// The removal is synced, so that a crash can't bring the entries back once new
+    /// ones are appended in their place.
+    fn truncate_from(&mut self, index: u64) -> Result<()> {
+        self.active = None;
+        while self.segments.last().is_some_and(|seg| seg.first_index >= index) {
+            let seg = self.segments.pop().unwrap();
+            fs::remove_file(&seg.path)?;
+        }
+        let should_sync = self.should_sync();
+        if let Some(seg) = self.segments.last_mut() {
+            if seg.last_index() >= index {
+                let keep = (index - seg.first_index) as usize;
+                seg.size = seg.offsets[keep];
+                seg.offsets.truncate(keep);
+                seg.terms.truncate(keep);
+                let file = OpenOptions::new().write(true).open(&seg.path)?;
+                file.set_len(seg.size)?;
+                if should_sync {
+                    file.sync_all()?;
+                }
+            }
+            self.active = Some(OpenOptions::new().append(true).open(&seg.path)?);
+        }
+        self.sync_wal_dir()
+    }
+
+    fn remove_segments(&mut self) -> Result<()> {
+        self.active = None;
+        for seg in self.segments.drain(..) {
+            fs::remove_file(&seg.path)?;
+        }
+        self.sync_wal_dir()
+    }
+
+    /// Makes the removal of segments durable.
+    fn sync_wal_dir(&self) -> Result<()> {
+        if self.should_sync() {
+            sync_dir(&self.dir.join(WAL_DIR))?;
+        }
+        Ok(())
+    }
+
+    fn persist_meta(&self) -> Result<()> {
+        let meta = SnapshotMetadata {
+            conf_state: Some(self.raft_state.conf_state.clone()),
+            index: self.truncated_index,
+            term: self.truncated_term,
+        };
+        let mut payload = self.meta_hard_state.encode_length_delimited_to_vec();
+        payload.extend(meta.encode_length_delimited_to_vec());
+        write_file_atomically(&self.dir.join(META_FILE), &payload, self.should_sync())
+    }
+
+    /// Writes the snapshot file and removes the previous one.
+    fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let meta = snapshot.metadata.as_ref().unwrap();
+        let name = format!("{:020}-{:020}.{}", meta.index, meta.term, SNAP_EXT);
+        let path = self.dir.join(SNAP_DIR).join(name);
+        write_file_atomically(&path, &snapshot.encode_to_vec(), self.should_sync())?;
+        if let Some((_, old)) = self.snapshot_file.replace((meta.index, path.clone())) {
+            if old != path {
+                fs::remove_file(old)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `FileStorage` is a thread-safe implementation of `Storage` that persists the raft
+/// log, the `HardState`, the `ConfState` and snapshots in a directory, so that a node
+/// can be restarted from it.
+///
+/// Like `MemStorage` it only contains raft logs; snapshots carry whatever data the
+/// application passes to `FileStorageCore::create_snapshot`.
+#[derive(Clone)]
+pub struct FileStorage {
+    core: Arc<RwLock<FileStorageCore>>,
+}
+
+impl FileStorage {
+    /// Opens the storage in `dir`, creating it if needed and recovering whatever
+    /// was written before a crash.
+    pub fn open(dir: impl AsRef<Path>, config: FileStorageConfig) -> Result<FileStorage> {
+        let core = FileStorageCore::open(dir.as_ref(), config)?;
+        Ok(FileStorage {
+            core: Arc::new(RwLock::new(core)),
+        })
+    }
+
+    /// Initialize a new `FileStorage` with a given `ConfState`.
+    ///
+    /// You should use the same input to initialize all nodes.
+    pub fn initialize_with_conf_state<T>(&self, conf_state: T) -> Result<()>
+    where
+        ConfState: From<T>,
+    {
+        assert!(!self.initial_state().unwrap().initialized());
+        self.wl().set_conf_state(ConfState::from(conf_state))
+    }
+
+    /// Opens up a read lock on the storage and returns a guard handle. Use this
+    /// with functions that don't require mutation.
+    pub fn rl(&self) -> RwLockReadGuard<'_, FileStorageCore> {
+        self.core.read().unwrap()
+    }
+
+    /// Opens up a write lock on the storage and returns guard handle. Use this
+    /// with functions that take a mutable reference to self.
+    pub fn wl(&self) -> RwLockWriteGuard<'_, FileStorageCore> {
+        self.core.write().unwrap()
+    }
+}
+
+impl Storage for FileStorage {
+    /// Implements the Storage trait.
+    fn initial_state(&self) -> Result<RaftState> {
+        Ok(self.rl().raft_state.clone())
+    }
+
+    /// Implements the Storage trait.
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        _context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
+        let core = self.rl();
+        if low < core.first_index() {
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        if high > core.last_index() + 1 {
+            panic!(
+                "index out of bound (last: {}, high: {})",
+                core.last_index() + 1,
+                high
+            );
+        }
+        core.read_entries(low, high, max_size.into())
+    }
+
+    /// Implements the Storage trait.
+    fn term(&self, idx: u64) -> Result<u64> {
+        self.rl().term(idx)
+    }
+
+    /// Implements the Storage trait.
+    fn first_index(&self) -> Result<u64> {
+        Ok(self.rl().first_index())
+    }
+
+    /// Implements the Storage trait.
+    fn last_index(&self) -> Result<u64> {
+        Ok(self.rl().last_index())
+    }
+
+    /// Implements the Storage trait. Returns SnapshotTemporarilyUnavailable until
+    /// the application has created a snapshot at or after `request_index`.
+    fn snapshot(&self, request_index: u64, _to: u64) -> Result<Snapshot> {
+        match &self.rl().snapshot_file {
+            Some((index, path)) if *index >= request_index => read_snapshot(path),
+            _ => Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable)),
+        }
+    }
+}
+
+fn corrupted(e: impl ToString) -> Error {
+    Error::Store(StorageError::Corrupted(e.to_string()))
+}
+
+fn encode_record(payload: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+/// Returns the payload of the record at the start of `buf`, or None if it is
+/// incomplete or fails its checksum.
+fn decode_record(buf: &[u8]) -> Option<&[u8]> {
+    let header = buf.get(..RECORD_HEADER_SIZE as usize)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let start = RECORD_HEADER_SIZE as usize;
+    let payload = buf.get(start..start + len)?;
+    (crc32fast::hash(payload) == crc).then_some(payload)
+}
+
+fn read_entry(reader: &mut impl Read, path: &Path) -> Result<Entry> {
+    let mut header = [0; RECORD_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let mut record = header.to_vec();
+    record.resize(header.len() + len, 0);
+    reader.read_exact(&mut record[header.len()..])?;
+    match decode_record(&record) {
+        Some(payload) => Entry::decode(payload).map_err(corrupted),
+        None => Err(corrupted(format!("bad record in {}", path.display()))),
+    }
+}
+
+fn read_record_file(path: &Path) -> Result<Vec<u8>> {
+    let buf = fs::read(path)?;
+    match decode_record(&buf) {
+        Some(payload) => Ok(payload.to_vec()),
+        None => Err(corrupted(format!("bad record in {}", path.display()))),
+    }
+}
+
+fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let payload = read_record_file(path)?;
+    Snapshot::decode(payload.as_slice()).map_err(corrupted)
+}
+
+/// Replaces `path` with a file holding one record of `payload`, so that readers see
+/// either the old or the new content.
+fn write_file_atomically(path: &Path, payload: &[u8], sync: bool) -> Result<()> {
+    let tmp = path.with_extension(TMP_EXT);
+    let mut buf = Vec::with_capacity(payload.len() + RECORD_HEADER_SIZE as usize);
+    encode_record(payload, &mut buf);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&buf)?;
+    if sync {
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    if sync {
+        sync_dir(path.parent().unwrap())?;
+    }
+    Ok(())
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Lists the `<stem>.<ext>` files in `dir`, removing temporary files left by a crash.
+fn list_files(dir: &Path, ext: &str) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let (stem, file_ext) = match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(file_ext)) => (stem.to_string_lossy(), file_ext),
+            _ => continue,
+        };
+        if file_ext == TMP_EXT {
+            fs::remove_file(&path)?;
+        } else if file_ext == ext {
+            files.push((stem.into_owned(), path));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            data: format!("entry {}", index).into_bytes(),
+            ..Default::default()
+        }
+    }
+
+    fn new_entries(low: u64, high: u64, term: u64) -> Vec<Entry> {
+        (low..high).map(|i| new_entry(i, term)).collect()
+    }
+
+    /// Small segments, so that a handful of entries span several files.
+    fn open(dir: &Path) -> FileStorage {
+        let config = FileStorageConfig {
+            segment_size: 64,
+            sync_policy: SyncPolicy::Always,
+        };
+        FileStorage::open(dir, config).unwrap()
+    }
+
+    fn segment_count(dir: &Path) -> usize {
+        fs::read_dir(dir.join(WAL_DIR)).unwrap().count()
+    }
+
+    fn all_entries(storage: &FileStorage) -> Vec<Entry> {
+        let (low, high) = (storage.first_index().unwrap(), storage.last_index().unwrap());
+        storage
+            .entries(low, high + 1, None, GetEntriesContext::empty(false))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_file_storage_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage
+            .initialize_with_conf_state(ConfState {
+                voters: vec![1, 2, 3],
+                ..Default::default()
+            })
+            .unwrap();
+        storage.wl().append(&new_entries(1, 11, 1)).unwrap();
+        let hs = HardState {
+            term: 1,
+            vote: 2,
+            commit: 8,
+        };
+        storage.wl().set_hardstate(hs.clone()).unwrap();
+        assert!(segment_count(dir.path()) > 1);
+        drop(storage);
+
+        let storage = open(dir.path());
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state, hs);
+        assert_eq!(state.conf_state.voters, vec![1, 2, 3]);
+        assert_eq!(storage.first_index().unwrap(), 1);
+        assert_eq!(storage.last_index().unwrap(), 10);
+        assert_eq!(all_entries(&storage), new_entries(1, 11, 1));
+        assert_eq!(storage.term(0).unwrap(), 0);
+        assert_eq!(storage.term(10).unwrap(), 1);
+
+        // The size limit is honoured across segments, returning at least one entry.
+        let size = new_entry(1, 1).data.len() as u64;
+        let ctx = GetEntriesContext::empty(false);
+        assert_eq!(storage.entries(2, 11, Some(size * 3), ctx).unwrap().len(), 3);
+        assert_eq!(storage.entries(2, 11, Some(1), ctx).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_file_storage_truncates_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage.wl().append(&new_entries(1, 4, 1)).unwrap();
+        let path = storage.rl().segments.last().unwrap().path.clone();
+        drop(storage);
+
+        // Cut the last record in half.
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        let storage = open(dir.path());
+        assert_eq!(storage.last_index().unwrap(), 2);
+        assert_eq!(all_entries(&storage), new_entries(1, 3, 1));
+        // The log continues where the valid part ends.
+        storage.wl().append(&new_entries(3, 5, 2)).unwrap();
+        drop(storage);
+        let storage = open(dir.path());
+        assert_eq!(storage.last_index().unwrap(), 4);
+        assert_eq!(storage.term(3).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_file_storage_corrupted_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage.wl().append(&new_entries(1, 11, 1)).unwrap();
+        let path = storage.rl().segments[0].path.clone();
+        drop(storage);
+
+        // Damage the first record of the first segment: this is not a torn write.
+        let mut buf = fs::read(&path).unwrap();
+        buf[RECORD_HEADER_SIZE as usize] ^= 0xff;
+        fs::write(&path, buf).unwrap();
+        let err = FileStorage::open(dir.path(), FileStorageConfig::default()).err();
+        assert!(matches!(
+            err,
+            Some(Error::Store(StorageError::Corrupted(_)))
+        ));
+    }
+
+    #[test]
+    fn test_file_storage_overwrite_and_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage.wl().append(&new_entries(1, 11, 1)).unwrap();
+        let segments = segment_count(dir.path());
+
+        // A conflicting append drops the tail, including whole segments.
+        storage.wl().append(&new_entries(4, 6, 2)).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 5);
+        assert!(segment_count(dir.path()) < segments);
+
+        storage.wl().append(&new_entries(6, 11, 2)).unwrap();
+        storage.wl().compact(7).unwrap();
+        assert_eq!(storage.first_index().unwrap(), 7);
+        assert_eq!(storage.term(6).unwrap(), 2);
+        assert!(matches!(
+            storage.term(5),
+            Err(Error::Store(StorageError::Compacted))
+        ));
+        let first_segment = storage.rl().segments[0].first_index;
+        assert!(first_segment > 1 && first_segment <= 7);
+        drop(storage);
+
+        let storage = open(dir.path());
+        assert_eq!(storage.first_index().unwrap(), 7);
+        let mut expected = new_entries(4, 11, 2);
+        expected.drain(..3);
+        assert_eq!(all_entries(&storage), expected);
+    }
+
+    #[test]
+    fn test_file_storage_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage
+            .initialize_with_conf_state(ConfState {
+                voters: vec![1],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(matches!(
+            storage.snapshot(0, 2),
+            Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable))
+        ));
+        storage.wl().append(&new_entries(1, 6, 1)).unwrap();
+        let mut hs = HardState {
+            term: 1,
+            vote: 1,
+            commit: 5,
+        };
+        storage.wl().set_hardstate(hs.clone()).unwrap();
+
+        storage.wl().create_snapshot(4, None, b"data".to_vec()).unwrap();
+        let snap = storage.snapshot(3, 2).unwrap();
+        assert_eq!(snap.data, b"data");
+        let meta = snap.metadata.unwrap();
+        assert_eq!((meta.index, meta.term), (4, 1));
+        assert!(storage.snapshot(5, 2).is_err());
+
+        // A snapshot from the leader replaces the whole log.
+        let snap = Snapshot {
+            data: b"leader".to_vec(),
+            metadata: Some(SnapshotMetadata {
+                conf_state: Some(ConfState {
+                    voters: vec![1, 2],
+                    ..Default::default()
+                }),
+                index: 20,
+                term: 3,
+            }),
+        };
+        storage.wl().apply_snapshot(snap).unwrap();
+        assert_eq!(segment_count(dir.path()), 0);
+        storage.wl().append(&new_entries(21, 23, 3)).unwrap();
+        drop(storage);
+
+        let storage = open(dir.path());
+        hs.term = 3;
+        hs.commit = 20;
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state, hs);
+        assert_eq!(state.conf_state.voters, vec![1, 2]);
+        assert_eq!(storage.first_index().unwrap(), 21);
+        assert_eq!(storage.term(20).unwrap(), 3);
+        assert_eq!(storage.snapshot(0, 2).unwrap().data, b"leader");
+        assert_eq!(fs::read_dir(dir.path().join(SNAP_DIR)).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_file_storage_drops_log_diverging_from_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage.wl().append(&new_entries(1, 11, 1)).unwrap();
+        // Simulate a crash in apply_snapshot after the metadata was replaced.
+        {
+            let mut core = storage.wl();
+            core.truncated_index = 5;
+            core.truncated_term = 2;
+            core.persist_meta().unwrap();
+        }
+        drop(storage);
+
+        let storage = open(dir.path());
+        assert_eq!(storage.first_index().unwrap(), 6);
+        assert_eq!(storage.last_index().unwrap(), 5);
+        assert_eq!(segment_count(dir.path()), 0);
+    }
+
+    #[test]
+    fn test_file_storage_hardstate_saved_after_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        let hs = HardState {
+            term: 1,
+            vote: 1,
+            commit: 3,
+        };
+        // Simulate a crash in flush after the entries are synced: the HardState
+        // committing them is not saved yet.
+        {
+            let mut core = storage.wl();
+            core.stage(&new_entries(1, 4, 1)).unwrap();
+            core.stage_hardstate(hs.clone());
+            assert_eq!(core.write_staged().unwrap(), Some(hs.clone()));
+            core.sync().unwrap();
+        }
+        drop(storage);
+
+        let storage = open(dir.path());
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state, HardState::default());
+        assert_eq!(storage.last_index().unwrap(), 3);
+
+        // A complete flush saves both.
+        storage.wl().stage(&new_entries(4, 6, 1)).unwrap();
+        storage.wl().set_hardstate(HardState { commit: 5, ..hs }).unwrap();
+        drop(storage);
+        let storage = open(dir.path());
+        assert_eq!(storage.initial_state().unwrap().hard_state.commit, 5);
+        assert_eq!(storage.last_index().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_file_storage_failed_write_can_be_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage.wl().append(&new_entries(1, 3, 1)).unwrap();
+        let path = storage.rl().segments.last().unwrap().path.clone();
+        let size = fs::metadata(&path).unwrap().len();
+
+        // A read-only handle makes the next write fail.
+        {
+            let mut core = storage.wl();
+            core.active = Some(File::open(&path).unwrap());
+            core.stage(&new_entries(3, 5, 1)).unwrap();
+            assert!(core.write_staged().is_err());
+            assert_eq!(core.segments.last().unwrap().size, size);
+            assert_eq!(core.last_index(), 4);
+            core.active = Some(OpenOptions::new().append(true).open(&path).unwrap());
+            core.flush().unwrap();
+        }
+        assert_eq!(all_entries(&storage), new_entries(1, 5, 1));
+        drop(storage);
+
+        let storage = open(dir.path());
+        assert_eq!(all_entries(&storage), new_entries(1, 5, 1));
+    }
+
+    #[test]
+    fn test_file_storage_flush_syncs_without_hardstate() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        let mut core = storage.wl();
+        core.stage(&new_entries(1, 3, 1)).unwrap();
+        // The HardState is unchanged, the entries must be synced all the same.
+        core.set_hardstate(HardState::default()).unwrap();
+        assert!(!core.dirty);
+        assert_eq!(core.written_last_index(), 2);
+    }
+}
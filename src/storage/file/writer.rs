@@ -0,0 +1,484 @@
+//! A group-commit writer for `FileStorage`.
+//!
+//! Appending straight to `FileStorage` with `SyncPolicy::Always` costs one fsync per
+//! call, which caps proposal throughput at the disk's sync rate. `WalWriter` instead
+//! stages the writes of every caller in the storage, so they can be read right away,
+//! and lets a background thread write whatever has been staged with a single write
+//! and make the entries durable with a single fsync. Callers that arrive while a
+//! sync is in flight are served together by the next one.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use raftpb::proto::{Entry, HardState};
+
+use super::FileStorage;
+use crate::errors::{Error, Result, StorageError};
+use crate::node::Ready;
+
+/// The default maximum number of requests written by a single batch.
+pub const DEFAULT_MAX_BATCH: usize = 1024;
+
+/// Statistics of a `WalWriter` since it was started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WalStats {
+    /// The number of batches written.
+    pub batches: u64,
+    /// The number of requests served by the batches.
+    pub requests: u64,
+    /// The number of entries written by the batches.
+    pub entries: u64,
+    /// The largest number of requests served by a single batch.
+    pub max_batch: u64,
+    /// The time spent writing and syncing batches, including the segments synced
+    /// when a batch fills one up and the metadata file.
+    pub write_total: Duration,
+    /// The longest time spent writing and syncing a batch.
+    pub write_max: Duration,
+    /// The number of batches that synced the segment they were written to.
+    pub syncs: u64,
+    /// The time spent syncing the segment written to by a batch.
+    pub sync_total: Duration,
+    /// The longest time spent syncing the segment written to by a batch.
+    pub sync_max: Duration,
+}
+
+impl WalStats {
+    /// The average number of requests served by a batch.
+    pub fn avg_batch(&self) -> f64 {
+        if self.batches == 0 {
+            return 0.0;
+        }
+        self.requests as f64 / self.batches as f64
+    }
+
+    /// The average time spent writing and syncing a batch.
+    pub fn avg_write(&self) -> Duration {
+        if self.batches == 0 {
+            return Duration::ZERO;
+        }
+        self.write_total / self.batches as u32
+    }
+
+    /// The average time spent syncing the segment written to by a batch.
+    pub fn avg_sync(&self) -> Duration {
+        if self.syncs == 0 {
+            return Duration::ZERO;
+        }
+        self.sync_total / self.syncs as u32
+    }
+}
+
+struct Request {
+    entries: u64,
+    done: Sender<Result<()>>,
+}
+
+/// Writes and syncs the appends of concurrent callers to a `FileStorage` in batches.
+///
+/// Every call to `submit` returns a receiver that is notified once the data of the
+/// call is durable. A node driven by `Node::advance_append_async` hands its readies
+/// to `persist_ready` and reports the ones `durable_ready` returns through
+/// `Node::on_persist_ready`; the persisted messages of a ready must be held back
+/// until then.
+///
+/// A batch first writes and syncs its entries, then saves the latest staged
+/// HardState by replacing the metadata file, so a HardState is never durable before
+/// the entries it may commit. A batch carrying a HardState thus costs a second sync.
+/// The entries are synced according to the `SyncPolicy` of the storage, as
+/// `FileStorageCore::flush` does: with `SyncPolicy::Batch`, a batch without a new
+/// HardState only syncs once the interval has elapsed, and with `SyncPolicy::Never`
+/// nothing is synced, so the data of a caller isn't guaranteed to be durable when
+/// it is notified.
+/// Readies should not be mixed with `FileStorageCore::set_hardstate` calls, which
+/// may be overtaken by the HardState of an earlier batch.
+///
+/// Once a batch fails, the writer fails every following request, as the state of the
+/// files on disk is unknown.
+pub struct WalWriter {
+    storage: FileStorage,
+    sender: Option<Sender<Request>>,
+    handle: Option<JoinHandle<()>>,
+    stats: Arc<Mutex<WalStats>>,
+    // The number of every ready passed to `persist_ready` that isn't known to be
+    // durable yet, in order.
+    readies: Mutex<VecDeque<(u64, Receiver<Result<()>>)>>,
+}
+
+impl WalWriter {
+    /// Starts a writer for `storage` serving at most `max_batch` requests per batch.
+    pub fn new(storage: FileStorage, max_batch: usize) -> Result<WalWriter> {
+        assert!(max_batch > 0, "max_batch must be greater than 0");
+        let (sender, receiver) = mpsc::channel();
+        let stats = Arc::new(Mutex::new(WalStats::default()));
+        let (s, st) = (storage.clone(), stats.clone());
+        let handle = thread::Builder::new()
+            .name("wal-writer".to_owned())
+            .spawn(move || run(s, receiver, max_batch, st))?;
+        Ok(WalWriter {
+            storage,
+            sender: Some(sender),
+            handle: Some(handle),
+            stats,
+            readies: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Stages `entries` and `hs` in the storage and queues them to be written. The
+    /// entries can be read through `Storage` once this returns; the receiver gets
+    /// the outcome once they are durable.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `entries` contains compacted entries, or there's a gap between
+    /// `entries` and the last received entry in the storage.
+    pub fn submit(&self, entries: &[Entry], hs: Option<HardState>) -> Result<Receiver<Result<()>>> {
+        let (done, receiver) = mpsc::channel();
+        let req = Request {
+            entries: entries.len() as u64,
+            done,
+        };
+        {
+            let mut core = self.storage.wl();
+            core.stage(entries)?;
+            if let Some(hs) = hs {
+                core.stage_hardstate(hs);
+            }
+        }
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(req)
+            .map_err(|_| writer_error("wal writer stopped"))?;
+        Ok(receiver)
+    }
+
+    /// Persists the snapshot, entries and HardState of a ready. The snapshot is
+    /// applied before this returns, the rest is written in the background and
+    /// reported by `durable_ready`.
+    pub fn persist_ready(&self, rd: &Ready) -> Result<()> {
+        if !rd.snapshot().is_empty() {
+            self.storage.wl().apply_snapshot(rd.snapshot().clone())?;
+        }
+        let receiver = self.submit(rd.entries(), rd.hs().cloned())?;
+        self.readies
+            .lock()
+            .unwrap()
+            .push_back((rd.number(), receiver));
+        Ok(())
+    }
+
+    /// Returns the number of the latest ready passed to `persist_ready` that is
+    /// durable along with every ready before it, if any became durable since the
+    /// last call. It is meant to be passed to `Node::on_persist_ready`.
+    pub fn durable_ready(&self) -> Result<Option<u64>> {
+        let mut readies = self.readies.lock().unwrap();
+        let mut durable = None;
+        while let Some((number, receiver)) = readies.front() {
+            match receiver.try_recv() {
+                Ok(res) => res?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Err(writer_error("wal writer stopped")),
+            }
+            durable = Some(*number);
+            readies.pop_front();
+        }
+        Ok(durable)
+    }
+
+    /// Returns the statistics of the batches written so far.
+    pub fn stats(&self) -> WalStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+impl Drop for WalWriter {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer finish the queued requests and exit.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run(
+    storage: FileStorage,
+    receiver: Receiver<Request>,
+    max_batch: usize,
+    stats: Arc<Mutex<WalStats>>,
+) {
+    let mut failure: Option<String> = None;
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        while batch.len() < max_batch {
+            match receiver.try_recv() {
+                Ok(req) => batch.push(req),
+                Err(_) => break,
+            }
+        }
+
+        let res = match &failure {
+            Some(msg) => Err(writer_error(msg)),
+            None => write_batch(&storage),
+        };
+        match res {
+            Ok((elapsed, synced)) => {
+                let mut stats = stats.lock().unwrap();
+                stats.batches += 1;
+                stats.requests += batch.len() as u64;
+                stats.entries += batch.iter().map(|req| req.entries).sum::<u64>();
+                stats.max_batch = stats.max_batch.max(batch.len() as u64);
+                stats.write_total += elapsed;
+                stats.write_max = stats.write_max.max(elapsed);
+                if let Some(synced) = synced {
+                    stats.syncs += 1;
+                    stats.sync_total += synced;
+                    stats.sync_max = stats.sync_max.max(synced);
+                }
+                drop(stats);
+                for req in batch {
+                    let _ = req.done.send(Ok(()));
+                }
+            }
+            Err(e) => {
+                let msg = failure.get_or_insert_with(|| e.to_string()).clone();
+                for req in batch {
+                    let _ = req.done.send(Err(writer_error(&msg)));
+                }
+            }
+        }
+    }
+}
+
+/// Writes the entries staged in the storage and syncs them outside of the lock, so
+/// that callers can keep staging meanwhile, then saves the HardState staged with
+/// them. Returns the time it took, and the time the sync took if there was one.
+fn write_batch(storage: &FileStorage) -> Result<(Duration, Option<Duration>)> {
+    let start = Instant::now();
+    let (hs, file) = {
+        let mut core = storage.wl();
+        let hs = core.write_staged()?;
+        let file = core.start_sync(hs.is_some())?;
+        (hs, file)
+    };
+    let mut synced = None;
+    if let Some(file) = file {
+        let sync_start = Instant::now();
+        file.sync_data()?;
+        synced = Some(sync_start.elapsed());
+    }
+    if let Some(hs) = hs {
+        storage.wl().persist_hardstate(hs)?;
+    }
+    Ok((start.elapsed(), synced))
+}
+
+// The errors of a batch are shared by all of its callers, so each gets a copy.
+fn writer_error(msg: &str) -> Error {
+    Error::Store(StorageError::Io(io::Error::other(msg.to_owned())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::node::Node;
+    use crate::storage::file::{FileStorageConfig, SyncPolicy};
+    use crate::storage::{GetEntriesContext, Storage};
+    use raftpb::proto::ConfState;
+    use slog::{o, Discard, Logger};
+    use std::path::Path;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            term,
+            index,
+            data: b"data".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn open(dir: &Path) -> FileStorage {
+        FileStorage::open(dir, FileStorageConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_concurrent_submits_share_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        let writer = Arc::new(WalWriter::new(storage.clone(), DEFAULT_MAX_BATCH).unwrap());
+        let next = Arc::new(Mutex::new(1u64));
+
+        // The writer records the stats of a batch before notifying its callers, so
+        // holding the stats stalls it after at most one batch, while the requests
+        // of every thread pile up behind it.
+        let stalled = writer.stats.lock().unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (writer, next) = (writer.clone(), next.clone());
+                thread::spawn(move || {
+                    let mut receivers = Vec::new();
+                    for _ in 0..50 {
+                        // Indexes are handed out under the lock so the log stays contiguous.
+                        let mut next = next.lock().unwrap();
+                        let rx = writer.submit(&[new_entry(*next, 1)], None).unwrap();
+                        *next += 1;
+                        receivers.push(rx);
+                    }
+                    receivers
+                })
+            })
+            .collect();
+        let receivers: Vec<_> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        drop(stalled);
+        for rx in receivers {
+            rx.recv().unwrap().unwrap();
+        }
+
+        let stats = writer.stats();
+        assert_eq!(stats.requests, 400);
+        assert_eq!(stats.entries, 400);
+        assert!(stats.batches < stats.requests);
+        assert!(stats.max_batch > 1);
+        assert!(stats.write_max <= stats.write_total);
+        assert_eq!(stats.syncs, stats.batches);
+        assert!(stats.sync_max <= stats.sync_total);
+        assert!(stats.sync_total <= stats.write_total);
+
+        drop(writer);
+        drop(storage);
+        let storage = open(dir.path());
+        assert_eq!(storage.last_index().unwrap(), 400);
+        let ents = storage.entries(1, 401, None, GetEntriesContext::empty(false)).unwrap();
+        assert_eq!(ents.len(), 400);
+    }
+
+    #[test]
+    fn test_submit_persists_hardstate() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        let writer = WalWriter::new(storage.clone(), DEFAULT_MAX_BATCH).unwrap();
+        let hs = HardState {
+            term: 2,
+            vote: 1,
+            commit: 1,
+        };
+        let rx = writer
+            .submit(&[new_entry(1, 1), new_entry(2, 2)], Some(hs.clone()))
+            .unwrap();
+        // Staged entries are readable before they are durable.
+        assert_eq!(storage.term(2).unwrap(), 2);
+        rx.recv().unwrap().unwrap();
+
+        drop(writer);
+        drop(storage);
+        let storage = open(dir.path());
+        assert_eq!(storage.initial_state().unwrap().hard_state, hs);
+        assert_eq!(storage.last_index().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_durable_ready_drives_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(dir.path());
+        storage
+            .initialize_with_conf_state(ConfState {
+                voters: vec![1],
+                ..Default::default()
+            })
+            .unwrap();
+        let writer = WalWriter::new(storage.clone(), DEFAULT_MAX_BATCH).unwrap();
+        let logger = Logger::root(Discard, o!());
+        let config = Config {
+            id: 1,
+            ..Default::default()
+        };
+        let mut node = Node::new(&config, storage.clone(), &logger).unwrap();
+        node.raft.become_candidate();
+        node.raft.become_leader();
+
+        let mut persisted = 0;
+        for _ in 0..3 {
+            node.propose(vec![], b"somedata".to_vec()).unwrap();
+            while node.has_ready() {
+                let rd = node.ready();
+                writer.persist_ready(&rd).unwrap();
+                node.advance_append_async(rd);
+            }
+            let start = Instant::now();
+            loop {
+                if let Some(number) = writer.durable_ready().unwrap() {
+                    persisted = number;
+                    node.on_persist_ready(number);
+                }
+                if writer.readies.lock().unwrap().is_empty() {
+                    break;
+                }
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert!(persisted > 0);
+
+        // The no-op entry of the leader and the three proposals are committed.
+        while node.has_ready() {
+            let rd = node.ready();
+            writer.persist_ready(&rd).unwrap();
+            node.advance_append_async(rd);
+        }
+        assert_eq!(node.raft.raft_log.committed, 4);
+        let stats = writer.stats();
+        assert!(stats.entries >= 4);
+
+        drop(writer);
+        drop(node);
+        drop(storage);
+        let storage = open(dir.path());
+        assert_eq!(storage.last_index().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_write_batch_follows_sync_policy() {
+        let hs = HardState {
+            term: 1,
+            vote: 1,
+            commit: 0,
+        };
+        for (policy, wsyncs) in [
+            (SyncPolicy::Always, 2),
+            // Only the batch saving a HardState syncs before the interval elapses.
+            (SyncPolicy::Batch(Duration::from_secs(3600)), 1),
+            (SyncPolicy::Never, 0),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let config = FileStorageConfig {
+                sync_policy: policy,
+                ..Default::default()
+            };
+            let storage = FileStorage::open(dir.path(), config).unwrap();
+            let writer = WalWriter::new(storage.clone(), DEFAULT_MAX_BATCH).unwrap();
+            let rx = writer.submit(&[new_entry(1, 1)], Some(hs.clone())).unwrap();
+            rx.recv().unwrap().unwrap();
+            let rx = writer.submit(&[new_entry(2, 1)], None).unwrap();
+            rx.recv().unwrap().unwrap();
+
+            let stats = writer.stats();
+            assert_eq!(stats.batches, 2, "{:?}", policy);
+            assert_eq!(stats.syncs, wsyncs, "{:?}", policy);
+            if wsyncs == 0 {
+                assert_eq!(stats.avg_sync(), Duration::ZERO);
+            }
+
+            drop(writer);
+            drop(storage);
+            let storage = open(dir.path());
+            assert_eq!(storage.initial_state().unwrap().hard_state, hs);
+            assert_eq!(storage.last_index().unwrap(), 2);
+        }
+    }
+}
@@ -1,13 +1,25 @@
+pub mod inflights;
 pub mod progress;
 pub mod state;
 
-use crate::{quorum::joint::Configuration as JointConfig, raft::VoteResult};
+use crate::quorum::joint::{AckedIndexer, Configuration as JointConfig, Index};
+use crate::raft::VoteResult;
 use getset::Getters;
 use progress::Progress;
-use state::ProgressState;
+use raftpb::proto::ConfState;
 use std::collections::{HashMap, HashSet};
 
 pub type ProgressMap = HashMap<u64, Progress>;
+
+impl AckedIndexer for ProgressMap {
+    fn acked_index(&self, voter_id: u64) -> Option<Index> {
+        self.get(&voter_id).map(|p| Index {
+            index: p.matched,
+            group_id: 0,
+        })
+    }
+}
+
 /// `ProgressTracker` contains several `Progress`es,
 /// which could be `Leader`, `Follower` and `Learner`.
 #[derive(Clone, Getters)]
@@ -18,44 +30,61 @@ pub struct ProgressTracker {
     #[get = "pub"]
     conf: Configuration,
     pub votes: HashMap<u64, bool>,
+
+    /// The capacity of the inflight window of each new progress.
+    max_inflight: usize,
 }
 
-impl Default for ProgressTracker {
-    fn default() -> Self {
+impl ProgressTracker {
+    /// Creates an empty tracker whose progresses allow `max_inflight` appends
+    /// in flight.
+    pub fn new(max_inflight: usize) -> Self {
         let voter = 0;
         let learner = 0;
 
-        return ProgressTracker {
+        ProgressTracker {
             progress: HashMap::with_capacity(voter + learner),
             conf: Configuration::with_capacity(voter, learner),
             votes: HashMap::with_capacity(voter),
+            max_inflight,
+        }
+    }
 
-        };
+    /// Drops every progress and the configuration, e.g. before restoring the
+    /// configuration of a snapshot.
+    pub fn clear(&mut self) {
+        self.progress.clear();
+        self.conf = Configuration::default();
+        self.votes.clear();
+    }
+
+    /// Returns the current configuration as a `ConfState`.
+    pub fn conf_state(&self) -> ConfState {
+        self.conf.to_conf_state()
+    }
+
+    /// Returns the ids of every tracked peer, voters and learners alike.
+    pub fn ids(&self) -> Vec<u64> {
+        self.progress.keys().copied().collect()
     }
-}
 
-impl ProgressTracker {
     pub fn voter_ids(&self) -> HashSet<u64> {
         self.conf.voters.ids()
     }
 
-    pub fn apply_conf(&mut self, conf: Configuration, _changes: Vec<(u64, u64)>, _next_idx: u64) {
+    pub fn apply_conf(&mut self, conf: Configuration, _changes: Vec<(u64, u64)>, next_idx: u64) {
         self.conf = conf;
         let ids = self.conf.voters.ids();
         // Remove nodes that are no longer in the configuration
         self.progress.retain(|id, _| ids.contains(id) || self.conf.learners.contains(id));
         
         // Add new nodes
-        for id in ids {
-            self.progress.entry(id).or_insert(Progress {
-                recent_active: true, // New nodes are considered active initially
-                state: ProgressState::Probe,
-            });
-        }
-        for id in &self.conf.learners {
-            self.progress.entry(*id).or_insert(Progress {
-                recent_active: true,
-                state: ProgressState::Probe,
+        for id in ids.iter().chain(&self.conf.learners) {
+            self.progress.entry(*id).or_insert_with(|| {
+                let mut pr = Progress::new(next_idx, self.max_inflight);
+                // New nodes are considered active initially
+                pr.recent_active = true;
+                pr
             });
         }
     }
@@ -77,10 +106,19 @@ impl ProgressTracker {
         }
     }
 
+    pub fn get(&self, id: u64) -> Option<&Progress> {
+        self.progress.get(&id)
+    }
+
     pub fn get_mut(&mut self, id: u64) -> Option<&mut Progress> {
         self.progress.get_mut(&id)
     }
 
+    /// Returns an iterator across all the nodes and their progress.
+    pub fn iter_mut(&mut self) -> impl ExactSizeIterator<Item = (&u64, &mut Progress)> {
+        self.progress.iter_mut()
+    }
+
     pub fn reset_votes(&mut self) {
         self.votes.clear();
     }
@@ -121,6 +159,25 @@ impl ProgressTracker {
         self.conf.voters.vote_result(|id| votes.get(&id).cloned())
     }
 
+    /// Returns the largest log index known to be replicated on a quorum
+    /// of voters in the active configuration.
+    pub fn maximal_committed_index(&self) -> u64 {
+        self.conf.voters.committed_index(&self.progress).index
+    }
+
+    /// Returns true if the set of ids in `potential_quorum` forms a quorum.
+    pub fn has_quorum(&self, potential_quorum: &HashSet<u64>) -> bool {
+        self.conf
+            .voters
+            .vote_result(|id| potential_quorum.get(&id).map(|_| true))
+            == VoteResult::Won
+    }
+
+    /// Returns true if the configuration has a single voter and no learners.
+    pub fn is_singleton(&self) -> bool {
+        self.conf.voters.is_singleton() && self.conf.learners.is_empty()
+    }
+
 
 }
 
@@ -136,6 +193,46 @@ pub struct Configuration {
     /// simplifies the implementation since it allows peers to have clarity about
     /// its current role without taking into account joint consensus.
     pub learners: HashSet<u64>,
+    /// When we turn a voter into a learner during a joint consensus transition,
+    /// we cannot add the learner directly when entering the joint state. This is
+    /// because this would violate the invariant that the intersection of
+    /// voters and learners is empty. For example, assume a Voter is removed and
+    /// immediately re-added as a learner (or in other words, it is demoted):
+    ///
+    /// Initially, the configuration will be
+    ///
+    ///   voters:   {1 2 3}
+    ///   learners: {}
+    ///
+    /// and we want to demote 3. Entering the joint configuration, we naively get
+    ///
+    ///   voters:   {1 2} & {1 2 3}
+    ///   learners: {3}
+    ///
+    /// but this violates the invariant (3 is both voter and learner). Instead,
+    /// we get
+    ///
+    ///   voters:   {1 2} & {1 2 3}
+    ///   learners: {}
+    ///   next_learners: {3}
+    ///
+    /// Where 3 is now still purely a voter, but we are remembering the intention
+    /// to make it a learner upon transitioning into the final configuration:
+    ///
+    ///   voters:   {1 2}
+    ///   learners: {3}
+    ///   next_learners: {}
+    ///
+    /// Note that next_learners is not used while adding a learner that is not
+    /// also a voter in the joint config. In this case, the learner is added
+    /// right away when entering the joint configuration, so that it is caught up
+    /// as soon as possible.
+    pub learners_next: HashSet<u64>,
+    /// True if the configuration is joint and a transition to the incoming
+    /// configuration should be carried out automatically by Raft when this is
+    /// possible. If false, the configuration will be joint until the application
+    /// initiates the transition manually.
+    pub auto_leave: bool,
 }
 
 impl Configuration {
@@ -143,7 +240,26 @@ impl Configuration {
     pub fn with_capacity(voters: usize, learners: usize) -> Self {
         Self {
             voters: JointConfig::with_capacity(voters),
-            learners: HashSet::with_capacity(learners)
+            learners: HashSet::with_capacity(learners),
+            learners_next: HashSet::new(),
+            auto_leave: false,
+        }
+    }
+
+    /// Returns the `ConfState` representing this configuration, e.g. for the
+    /// application to persist.
+    pub fn to_conf_state(&self) -> ConfState {
+        let sorted = |ids: &HashSet<u64>| {
+            let mut ids: Vec<u64> = ids.iter().copied().collect();
+            ids.sort_unstable();
+            ids
+        };
+        ConfState {
+            voters: sorted(&self.voters.incoming.voters),
+            learners: sorted(&self.learners),
+            voters_outgoing: sorted(&self.voters.outgoing.voters),
+            learners_next: sorted(&self.learners_next),
+            auto_leave: self.auto_leave,
         }
     }
 }
@@ -0,0 +1,137 @@
+/// A buffer of inflight messages.
+///
+/// It holds the last index of each append sent to a peer and not yet
+/// acknowledged, so the leader can bound how far it runs ahead of a follower.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Inflights {
+    // the starting index in the buffer
+    start: usize,
+    // number of inflights in the buffer
+    count: usize,
+
+    // ring buffer
+    buffer: Vec<u64>,
+
+    // capacity
+    cap: usize,
+}
+
+impl Inflights {
+    /// Creates a new buffer for inflight messages.
+    pub fn new(cap: usize) -> Inflights {
+        Inflights {
+            buffer: Vec::with_capacity(cap),
+            start: 0,
+            count: 0,
+            cap,
+        }
+    }
+
+    /// Returns true if the inflights is full.
+    #[inline]
+    pub fn full(&self) -> bool {
+        self.count == self.cap
+    }
+
+    /// Adds an inflight into inflights
+    pub fn add(&mut self, inflight: u64) {
+        if self.full() {
+            panic!("cannot add into a full inflights")
+        }
+
+        let mut next = self.start + self.count;
+        if next >= self.cap {
+            next -= self.cap;
+        }
+
+        assert!(next <= self.buffer.len());
+        if next == self.buffer.len() {
+            self.buffer.push(inflight);
+        } else {
+            self.buffer[next] = inflight;
+        }
+
+        self.count += 1;
+    }
+
+    /// Frees the inflights smaller or equal to the given `to` flight.
+    pub fn free_to(&mut self, to: u64) {
+        if self.count == 0 || to < self.buffer[self.start] {
+            // out of the left side of the window
+            return;
+        }
+
+        let mut i = 0usize;
+        let mut idx = self.start;
+        while i < self.count {
+            if to < self.buffer[idx] {
+                // found the first large inflight
+                break;
+            }
+
+            // increase index and maybe rotate
+            idx += 1;
+            if idx >= self.cap {
+                idx -= self.cap;
+            }
+
+            i += 1;
+        }
+
+        // free i inflights and set new start index
+        self.count -= i;
+        self.start = idx;
+    }
+
+    /// Frees the first buffer entry.
+    #[inline]
+    pub fn free_first_one(&mut self) {
+        if self.count > 0 {
+            let start = self.buffer[self.start];
+            self.free_to(start);
+        }
+    }
+
+    /// Frees all inflights.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.start = 0;
+    }
+
+    /// Number of inflight messages.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Inflights;
+
+    #[test]
+    fn test_inflights_add_and_free() {
+        let mut inflight = Inflights::new(4);
+        for i in 1..=4 {
+            inflight.add(i);
+        }
+        assert!(inflight.full());
+
+        inflight.free_to(2);
+        assert_eq!(inflight.count(), 2);
+        // Wrap around the end of the ring buffer.
+        inflight.add(5);
+        inflight.add(6);
+        assert!(inflight.full());
+        assert_eq!(inflight.buffer, vec![5, 6, 3, 4]);
+
+        inflight.free_to(1);
+        assert_eq!(inflight.count(), 4);
+        inflight.free_first_one();
+        assert_eq!(inflight.count(), 3);
+        inflight.free_to(6);
+        assert_eq!(inflight.count(), 0);
+        assert!(!inflight.full());
+    }
+}